@@ -0,0 +1,550 @@
+//! Command-line client for the Mi Home cloud, sharing the `miio` crate with the app.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{Parser, Subcommand};
+use miio::{
+    DeviceListOptions, DeviceSelector, Effect, Error, ExportFormat, MiCloudProtocol,
+    MiCloudSession, Replay, Result, Scene, TrafficRecorder, UrlsConfig, YeelightBulb,
+    DEFAULT_BATCH_CONCURRENCY,
+};
+use serde_json::Value;
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+#[derive(Parser)]
+#[command(name = "miio-cli", version, about)]
+struct Cli {
+    /// Session file written by `login` and used by the other commands
+    #[arg(
+        long,
+        global = true,
+        env = "MIIO_SESSION",
+        default_value = "miio-session.json"
+    )]
+    session: PathBuf,
+    /// JSON file overriding account and API endpoints, see `UrlsConfig`
+    #[arg(long, global = true, env = "MIIO_ENDPOINTS")]
+    endpoints: Option<PathBuf>,
+    /// Append every HTTP exchange to this JSONL file, with secrets redacted
+    #[arg(long, global = true)]
+    record: Option<PathBuf>,
+    /// Answer requests from a file written by `--record` instead of the network
+    #[arg(long, global = true)]
+    replay: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Log in and save the session
+    Login {
+        #[arg(long)]
+        email: Option<String>,
+        /// Prompted for when omitted
+        #[arg(long, env = "MIIO_PASSWORD", hide_env_values = true)]
+        password: Option<String>,
+        /// Server location, see `countries`
+        #[arg(long)]
+        country: Option<String>,
+    },
+    /// List devices with their tokens
+    Devices {
+        /// Print the raw device list as JSON
+        #[arg(long, conflicts_with = "format")]
+        json: bool,
+        /// home-assistant, python-miio, csv or miio-cli
+        #[arg(long, default_value = "miio-cli")]
+        format: ExportFormat,
+        #[command(flatten)]
+        list: ListArgs,
+    },
+    /// Print a single device as JSON
+    Device { did: String },
+    /// Print homes with their rooms and device ids as JSON
+    Homes,
+    /// Call a miIO method on every selected device and print a JSON report
+    Batch {
+        method: String,
+        params: Option<String>,
+        #[command(flatten)]
+        select: SelectArgs,
+        /// Only devices of this room of `--home`
+        #[arg(long, requires = "home")]
+        room: Option<String>,
+        /// Requests in flight at once
+        #[arg(long, default_value_t = DEFAULT_BATCH_CONCURRENCY)]
+        concurrency: usize,
+    },
+    /// Turn LAN control of Yeelight bulbs on, or off with `--off`
+    LanControl {
+        #[arg(required_unless_present = "all")]
+        did: Option<String>,
+        #[arg(long, conflicts_with = "all")]
+        off: bool,
+        /// Every online bulb that supports it
+        #[arg(long, conflicts_with = "did")]
+        all: bool,
+    },
+    /// Find Yeelight bulbs with LAN control on and match them with the device list
+    Bulbs {
+        /// Seconds to wait for replies
+        #[arg(long, default_value_t = 3)]
+        wait: u64,
+    },
+    /// Control a Yeelight bulb over the LAN and print its state
+    Bulb {
+        did: String,
+        #[arg(long, value_parser = ["on", "off"])]
+        power: Option<String>,
+        /// 1-100
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+        bright: Option<u8>,
+        /// Color as RRGGBB hex
+        #[arg(long)]
+        rgb: Option<String>,
+        /// Color temperature in Kelvin
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1700..=6500))]
+        ct: Option<u16>,
+        /// Keep printing property changes as JSON lines
+        #[arg(long)]
+        watch: bool,
+    },
+    /// Print scenes and automations as JSON
+    Scenes {
+        /// Only scenes of this home, see `homes`
+        #[arg(long = "home")]
+        home_id: Option<String>,
+        /// Only scenes this device triggers or is controlled by
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// Run a manual scene
+    RunScene { id: String },
+    /// Turn an automation on or off
+    EnableScene {
+        id: String,
+        #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
+        enabled: bool,
+    },
+    /// Call a miIO method on a device, `params` is a JSON value
+    Call {
+        did: String,
+        method: String,
+        params: Option<String>,
+    },
+    /// List available server locations
+    Countries,
+    /// Change the server location of the saved session
+    SetCountry { country: String },
+}
+
+#[derive(clap::Args)]
+struct ListArgs {
+    /// Include devices shared by other accounts
+    #[arg(long)]
+    shared: bool,
+    /// Include virtual models such as light groups
+    #[arg(long = "virtual")]
+    virtual_models: bool,
+    /// Include Huami wearables
+    #[arg(long)]
+    huami: bool,
+    /// Include devices of every home
+    #[arg(long)]
+    all_homes: bool,
+    /// Only list devices of this home, repeatable, see `homes`
+    #[arg(long = "home")]
+    home_ids: Vec<String>,
+    /// Fetch bind keys of BLE devices
+    #[arg(long)]
+    ble_keys: bool,
+}
+
+impl From<ListArgs> for DeviceListOptions {
+    fn from(args: ListArgs) -> Self {
+        DeviceListOptions {
+            shared: args.shared,
+            virtual_models: args.virtual_models,
+            huami: args.huami,
+            all_homes: args.all_homes,
+            home_ids: args.home_ids,
+            ble_keys: args.ble_keys,
+        }
+    }
+}
+
+#[derive(clap::Args)]
+#[group(required = true, multiple = false)]
+struct SelectArgs {
+    /// Device id, repeatable
+    #[arg(long = "did")]
+    dids: Vec<String>,
+    /// Model glob, e.g. `yeelink.light.*`
+    #[arg(long)]
+    model: Option<String>,
+    /// Every device of this home, see `homes`
+    #[arg(long)]
+    home: Option<String>,
+}
+
+impl SelectArgs {
+    fn into_selector(self, room: Option<String>) -> DeviceSelector {
+        match (self.model, self.home) {
+            (Some(pattern), _) => DeviceSelector::Model { pattern },
+            (_, Some(home_id)) => DeviceSelector::Home {
+                home_id,
+                room_id: room,
+            },
+            _ => DeviceSelector::Dids { dids: self.dids },
+        }
+    }
+}
+
+enum Challenge {
+    Captcha(String),
+    TwoFactor { flag: String, error: String },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let urls = cli.endpoints.map(UrlsConfig::from_file).transpose()?;
+    let setup = |mut protocol: MiCloudProtocol| {
+        if let Some(urls) = &urls {
+            protocol._override_urls(urls.clone());
+        }
+        if let Some(path) = &cli.record {
+            protocol.set_traffic_recorder(Some(TrafficRecorder::create(path)?));
+        }
+        Ok::<_, Error>(protocol)
+    };
+    let load_session = |path: &Path| match &cli.replay {
+        Some(replay) => setup(MiCloudProtocol::replaying(Replay::from_file(replay)?)),
+        None => setup(load_session(path)?),
+    };
+    match cli.command {
+        Command::Login {
+            email,
+            password,
+            country,
+        } => {
+            let protocol = match &cli.replay {
+                Some(replay) => MiCloudProtocol::replaying(Replay::from_file(replay)?),
+                None => MiCloudProtocol::new(),
+            };
+            login(&cli.session, setup(protocol)?, email, password, country).await
+        }
+        Command::Devices { json, format, list } => {
+            let devices = load_session(&cli.session)?
+                .list_devices(&list.into(), None)
+                .await?;
+            if json {
+                print_json(&serde_json::to_value(devices)?)
+            } else {
+                print!("{}", miio::export_devices(&devices, format)?);
+                Ok(())
+            }
+        }
+        Command::Device { did } => {
+            let device = load_session(&cli.session)?.get_device(&did, None).await?;
+            print_json(&serde_json::to_value(device)?)
+        }
+        Command::Homes => {
+            let homes = load_session(&cli.session)?.get_homes(None).await?;
+            print_json(&serde_json::to_value(homes)?)
+        }
+        Command::Batch {
+            method,
+            params,
+            select,
+            room,
+            concurrency,
+        } => {
+            let params = params.as_deref().map(Value::from_str).transpose()?;
+            let report = load_session(&cli.session)?
+                .call_devices(
+                    &select.into_selector(room),
+                    &method,
+                    params,
+                    concurrency,
+                    None,
+                )
+                .await?;
+            eprintln!("{} ok, {} failed", report.succeeded(), report.failed());
+            print_json(&serde_json::to_value(report)?)
+        }
+        Command::LanControl { did, off, all } => {
+            let protocol = load_session(&cli.session)?;
+            match did {
+                Some(did) if !all => protocol.set_lan_control(&did, !off, None).await,
+                _ => {
+                    let report = protocol
+                        .enable_lan_control_all(DEFAULT_BATCH_CONCURRENCY, None)
+                        .await?;
+                    eprintln!("{} ok, {} failed", report.succeeded(), report.failed());
+                    print_json(&serde_json::to_value(report)?)
+                }
+            }
+        }
+        Command::Bulbs { wait } => {
+            let bulbs = miio::discover_bulbs(Duration::from_secs(wait)).await?;
+            let devices = match load_session(&cli.session) {
+                Ok(protocol) => protocol.get_devices(None, None).await?,
+                Err(Error::NotLoggedIn) => vec![],
+                Err(e) => return Err(e),
+            };
+            print_json(&serde_json::to_value(miio::match_bulbs(devices, bulbs))?)
+        }
+        Command::Bulb {
+            did,
+            power,
+            bright,
+            rgb,
+            ct,
+            watch,
+        } => {
+            let device = load_session(&cli.session)?
+                .get_device(&did, None)
+                .await?
+                .into_iter()
+                .next()
+                .ok_or_else(|| Error::Decode(format!("Unknown device {}", did)))?;
+            let mut bulb = YeelightBulb::from_device(&device).await?;
+            let effect = Effect::Smooth(Duration::from_millis(300));
+            if let Some(power) = power {
+                bulb.set_power(power == "on", effect).await?;
+            }
+            if let Some(bright) = bright {
+                bulb.set_bright(bright, effect).await?;
+            }
+            if let Some(rgb) = rgb {
+                let value = u32::from_str_radix(rgb.trim_start_matches('#'), 16)
+                    .map_err(|_| Error::Decode(format!("Invalid color {}", rgb)))?;
+                bulb.set_rgb(value, effect).await?;
+            }
+            if let Some(ct) = ct {
+                bulb.set_ct_abx(ct, effect).await?;
+            }
+            let state = bulb
+                .get_prop(&["power", "bright", "color_mode", "ct", "rgb"])
+                .await?;
+            print_json(&Value::Object(state))?;
+            if !watch {
+                return Ok(());
+            }
+            loop {
+                let props = bulb.next_props().await?;
+                println!("{}", serde_json::to_string(&props)?);
+            }
+        }
+        Command::Scenes { home_id, device } => {
+            let mut scenes = load_session(&cli.session)?
+                .get_scenes(home_id.as_deref(), None)
+                .await?;
+            if let Some(did) = device {
+                scenes.retain(|scene| scene.references(&did));
+            }
+            print_json(&serde_json::to_value(scenes)?)
+        }
+        Command::RunScene { id } => {
+            let protocol = load_session(&cli.session)?;
+            let scene = find_scene(&protocol, &id).await?;
+            protocol.run_scene(&scene, None).await
+        }
+        Command::EnableScene { id, enabled } => {
+            let protocol = load_session(&cli.session)?;
+            let scene = find_scene(&protocol, &id).await?;
+            protocol.set_scene_enabled(&scene, enabled, None).await
+        }
+        Command::Call {
+            did,
+            method,
+            params,
+        } => {
+            let params = params.as_deref().map(Value::from_str).transpose()?;
+            let result = load_session(&cli.session)?
+                .call_device(&did, &method, params, None)
+                .await?;
+            print_json(&result)
+        }
+        Command::Countries => {
+            for country in MiCloudProtocol::new().get_available_countries() {
+                println!("{}\t{}", country[0], country[1]);
+            }
+            Ok(())
+        }
+        Command::SetCountry { country } => {
+            let mut protocol = load_session(&cli.session)?;
+            if !protocol.is_country_supported(&country) {
+                return Err(Error::UnsupportedCountry(country));
+            }
+            protocol.set_country(&country);
+            save_session(&cli.session, &protocol)
+        }
+    }
+}
+
+async fn find_scene(protocol: &MiCloudProtocol, id: &str) -> Result<Scene> {
+    protocol
+        .get_scenes(None, None)
+        .await?
+        .into_iter()
+        .find(|scene| scene.id == id)
+        .ok_or_else(|| Error::Decode(format!("Unknown scene {}", id)))
+}
+
+async fn login(
+    path: &Path,
+    mut protocol: MiCloudProtocol,
+    email: Option<String>,
+    password: Option<String>,
+    country: Option<String>,
+) -> Result<()> {
+    if let Some(country) = country {
+        if !protocol.is_country_supported(&country) {
+            return Err(Error::UnsupportedCountry(country));
+        }
+        protocol.set_country(&country);
+    }
+    let email = match email {
+        Some(email) => email,
+        None => prompt("Email: ")?.unwrap_or_default(),
+    };
+    let password = match password {
+        Some(password) => password,
+        None => rpassword::prompt_password("Password: ")?,
+    };
+
+    let (tx, challenges) = mpsc::unbounded_channel();
+    let captcha_tx = tx.clone();
+    protocol._set_captcha_handler(Box::new(move |url| {
+        let _ = captcha_tx.send(Challenge::Captcha(url));
+    }));
+    protocol._set_two_factor_handler(Box::new(move |flag, error| {
+        let _ = tx.send(Challenge::TwoFactor { flag, error });
+    }));
+    // Clones share the challenge state, so the answers resume `login`
+    let answering = tokio::spawn(answer_challenges(protocol.clone(), challenges));
+    let result = protocol.login(&email, &password).await;
+    answering.abort();
+    result?;
+
+    save_session(path, &protocol)?;
+    eprintln!("Session saved to {}", path.display());
+    Ok(())
+}
+
+async fn answer_challenges(
+    solver: MiCloudProtocol,
+    mut challenges: mpsc::UnboundedReceiver<Challenge>,
+) {
+    while let Some(challenge) = challenges.recv().await {
+        match challenge {
+            Challenge::Captcha(data_url) => {
+                let answer = match save_captcha(&data_url) {
+                    Ok(path) => {
+                        eprintln!("Captcha image saved to {}", path.display());
+                        prompt_blocking("Captcha (empty line for a new image): ").await
+                    }
+                    Err(e) => {
+                        eprintln!("error: failed to save captcha: {e}");
+                        None
+                    }
+                };
+                match answer {
+                    Some(code) => solver.captcha_solve(&code).await,
+                    None => solver.captcha_cancel().await,
+                }
+            }
+            Challenge::TwoFactor { flag, error } => {
+                if !error.is_empty() {
+                    eprintln!("{error}");
+                }
+                let target = if flag == "4" { "phone" } else { "email" };
+                match prompt_blocking(&format!("Verification code sent to your {target}: ")).await {
+                    Some(code) => solver.two_factor_solve(&code).await,
+                    None => solver.two_factor_cancel().await,
+                }
+            }
+        }
+    }
+}
+
+/// Decodes a `data:image/...;base64,` URL into a file in the temp dir.
+fn save_captcha(data_url: &str) -> Result<PathBuf> {
+    let (meta, data) = data_url
+        .split_once(',')
+        .ok_or_else(|| Error::Decode("Captcha is not a data URL".to_string()))?;
+    let extension = meta
+        .strip_prefix("data:image/")
+        .and_then(|m| m.split(';').next())
+        .unwrap_or("jpeg");
+    let image = STANDARD
+        .decode(data)
+        .map_err(|e| Error::Decode(e.to_string()))?;
+    let path = std::env::temp_dir().join(format!("miio-captcha.{extension}"));
+    fs::write(&path, image)?;
+    Ok(path)
+}
+
+/// Reads a trimmed line from stdin, `None` on EOF.
+fn prompt(message: &str) -> Result<Option<String>> {
+    eprint!("{message}");
+    io::stderr().flush()?;
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim().to_string()))
+}
+
+async fn prompt_blocking(message: &str) -> Option<String> {
+    let message = message.to_string();
+    tokio::task::spawn_blocking(move || prompt(&message))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .flatten()
+}
+
+fn load_session(path: &Path) -> Result<MiCloudProtocol> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(Error::NotLoggedIn),
+        Err(e) => return Err(e.into()),
+    };
+    let session: MiCloudSession = serde_json::from_str(&data)?;
+    Ok(MiCloudProtocol::from_session(session))
+}
+
+fn save_session(path: &Path, protocol: &MiCloudProtocol) -> Result<()> {
+    let session = protocol.get_session().ok_or(Error::NotLoggedIn)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(&session)?)?;
+    Ok(())
+}
+
+fn print_json(value: &Value) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
@@ -0,0 +1,253 @@
+//! Registry of logged-in Mi Cloud accounts keyed by user id.
+
+use serde::Serialize;
+
+use crate::{Device, Error, MiCloudProtocol, MiCloudSession, Result};
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AccountInfo {
+    pub user_id: String,
+    pub username: Option<String>,
+    pub country: String,
+    pub active: bool,
+}
+
+/// Device tagged with the account it was listed by.
+#[derive(Serialize, Debug)]
+pub struct AccountDevice {
+    pub user_id: String,
+    #[serde(flatten)]
+    pub device: Device,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AccountError {
+    pub user_id: String,
+    pub error: Error,
+}
+
+/// Devices of every account. Accounts that failed to answer are listed in `errors`.
+#[derive(Serialize, Debug)]
+pub struct MergedDevices {
+    pub devices: Vec<AccountDevice>,
+    pub errors: Vec<AccountError>,
+}
+
+/// Logged-in accounts, one of which is active.
+///
+/// Account protocols are cloned from `template`, so the captcha/2FA handlers
+/// and challenge state set on it are shared by all of them.
+pub struct AccountRegistry {
+    template: MiCloudProtocol,
+    accounts: Vec<MiCloudProtocol>,
+    active: Option<String>,
+}
+
+impl AccountRegistry {
+    pub fn new(template: MiCloudProtocol) -> Self {
+        AccountRegistry {
+            template,
+            accounts: vec![],
+            active: None,
+        }
+    }
+
+    /// Returns a logged-out protocol to run `login` on before `insert`.
+    pub fn new_protocol(&self) -> MiCloudProtocol {
+        let mut protocol = self.template.clone();
+        protocol.logout();
+        protocol
+    }
+
+    /// Adds saved sessions, keeping `active` if it is one of them.
+    pub fn restore(&mut self, sessions: Vec<MiCloudSession>, active: Option<String>) {
+        for session in sessions {
+            let mut protocol = self.new_protocol();
+            protocol.set_session(session);
+            let _ = self.insert(protocol);
+        }
+        if let Some(user_id) = active {
+            let _ = self.switch(&user_id);
+        }
+    }
+
+    pub fn sessions(&self) -> Vec<MiCloudSession> {
+        self.accounts
+            .iter()
+            .filter_map(|a| a.get_session())
+            .collect()
+    }
+
+    /// Adds a logged-in protocol, replacing an account with the same user id,
+    /// and makes it active. Returns the user id.
+    pub fn insert(&mut self, protocol: MiCloudProtocol) -> Result<String> {
+        let user_id = protocol.user_id().ok_or(Error::NotLoggedIn)?;
+        match self.position(&user_id) {
+            Some(i) => self.accounts[i] = protocol,
+            None => self.accounts.push(protocol),
+        }
+        self.active = Some(user_id.clone());
+        Ok(user_id)
+    }
+
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// Looks up an account, `None` selects the active one.
+    pub fn get(&self, user_id: Option<&str>) -> Result<&MiCloudProtocol> {
+        let i = self.resolve(user_id)?;
+        Ok(&self.accounts[i])
+    }
+
+    pub fn get_mut(&mut self, user_id: Option<&str>) -> Result<&mut MiCloudProtocol> {
+        let i = self.resolve(user_id)?;
+        Ok(&mut self.accounts[i])
+    }
+
+    pub fn list(&self) -> Vec<AccountInfo> {
+        self.accounts
+            .iter()
+            .filter_map(|a| {
+                let user_id = a.user_id()?;
+                Some(AccountInfo {
+                    active: self.active.as_ref() == Some(&user_id),
+                    user_id,
+                    username: a.username.clone(),
+                    country: a.country.clone(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn switch(&mut self, user_id: &str) -> Result<()> {
+        self.resolve(Some(user_id))?;
+        self.active = Some(user_id.to_string());
+        Ok(())
+    }
+
+    /// Removes an account, `None` selects the active one. When the active
+    /// account is removed the first remaining one becomes active.
+    pub fn remove(&mut self, user_id: Option<&str>) -> Result<MiCloudProtocol> {
+        let i = self.resolve(user_id)?;
+        let removed = self.accounts.remove(i);
+        if self.active == removed.user_id() {
+            self.active = self.accounts.first().and_then(|a| a.user_id());
+        }
+        Ok(removed)
+    }
+
+    /// Lists devices of all accounts, each in the account's own country.
+    pub async fn get_all_devices(&self) -> MergedDevices {
+        let mut merged = MergedDevices {
+            devices: vec![],
+            errors: vec![],
+        };
+        for account in &self.accounts {
+            let Some(user_id) = account.user_id() else {
+                continue;
+            };
+            match account.get_devices(None, None).await {
+                Ok(devices) => {
+                    merged
+                        .devices
+                        .extend(devices.into_iter().map(|device| AccountDevice {
+                            user_id: user_id.clone(),
+                            device,
+                        }))
+                }
+                Err(error) => merged.errors.push(AccountError { user_id, error }),
+            }
+        }
+        merged
+    }
+
+    fn position(&self, user_id: &str) -> Option<usize> {
+        self.accounts
+            .iter()
+            .position(|a| a.user_id().as_deref() == Some(user_id))
+    }
+
+    fn resolve(&self, user_id: Option<&str>) -> Result<usize> {
+        match user_id.or(self.active.as_deref()) {
+            Some(user_id) => self
+                .position(user_id)
+                .ok_or_else(|| Error::UnknownAccount(user_id.to_string())),
+            None => Err(Error::NotLoggedIn),
+        }
+    }
+}
+
+impl Default for AccountRegistry {
+    fn default() -> Self {
+        Self::new(MiCloudProtocol::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(user_id: &str, country: &str) -> MiCloudSession {
+        MiCloudSession {
+            user_id: user_id.to_string(),
+            ssecurity: "9wR21gAtfAyn+KDX1ok/Iw==".to_string(),
+            service_token: format!("token-{user_id}"),
+            client_id: "android_client".to_string(),
+            country: country.to_string(),
+            username: Some(format!("{user_id}@example.com")),
+        }
+    }
+
+    #[test]
+    fn select_switch_remove() {
+        let mut registry = AccountRegistry::default();
+        assert!(matches!(registry.get(None), Err(Error::NotLoggedIn)));
+
+        registry.restore(
+            vec![session("1", "de"), session("2", "us")],
+            Some("1".to_string()),
+        );
+        assert_eq!(registry.active(), Some("1"));
+        assert_eq!(registry.get(None).unwrap().country, "de");
+        assert_eq!(registry.get(Some("2")).unwrap().country, "us");
+        assert!(matches!(
+            registry.get(Some("3")),
+            Err(Error::UnknownAccount(id)) if id == "3"
+        ));
+
+        registry.switch("2").unwrap();
+        let active: Vec<_> = registry
+            .list()
+            .into_iter()
+            .map(|a| (a.user_id, a.active))
+            .collect();
+        assert_eq!(
+            active,
+            vec![("1".to_string(), false), ("2".to_string(), true)]
+        );
+
+        registry.remove(None).unwrap();
+        assert_eq!(registry.active(), Some("1"));
+        assert_eq!(registry.sessions(), vec![session("1", "de")]);
+    }
+
+    #[test]
+    fn insert_replaces_same_user() {
+        let mut registry = AccountRegistry::default();
+        assert!(matches!(
+            registry.insert(registry.new_protocol()),
+            Err(Error::NotLoggedIn)
+        ));
+
+        registry.restore(vec![session("1", "de"), session("2", "us")], None);
+        assert_eq!(registry.active(), Some("2"));
+        let user_id = registry
+            .insert(MiCloudProtocol::from_session(session("1", "sg")))
+            .unwrap();
+        assert_eq!(user_id, "1");
+        assert_eq!(registry.active(), Some("1"));
+        assert_eq!(registry.list().len(), 2);
+        assert_eq!(registry.get(None).unwrap().country, "sg");
+    }
+}
@@ -1,7 +1,8 @@
-use anyhow::anyhow;
 use std::sync::{Arc, Mutex};
 use tokio::sync::oneshot;
 
+use crate::Error;
+
 pub enum ChallengeSolution<T> {
     Solved(T),
     Cancel,
@@ -27,16 +28,23 @@ impl<T: Send + 'static> AsyncChallengeState<T> {
         &self,
         payload: String,
         when_ready: F,
-    ) -> anyhow::Result<ChallengeSolution<T>>
+    ) -> crate::Result<ChallengeSolution<T>>
     where
         F: FnOnce(String) -> Fut,
         Fut: std::future::Future<Output = ()>,
     {
-        self.cancel().await;
         let (tx, rx) = oneshot::channel();
 
         {
             let mut lock = self.mutex.lock().unwrap();
+            // Every account shares this state, so a second login must not take
+            // over the challenge another one is still waiting on. A closed
+            // sender belongs to a login that was dropped and can be replaced.
+            if lock.as_ref().is_some_and(|p| !p.sender.is_closed()) {
+                return Err(Error::Login(
+                    "Another login is waiting for its challenge to be solved".to_string(),
+                ));
+            }
             *lock = Some(PendingChallenge { sender: tx });
         }
 
@@ -44,10 +52,10 @@ impl<T: Send + 'static> AsyncChallengeState<T> {
 
         match rx.await {
             Ok(solution) => Ok(solution),
-            Err(e) => Err(anyhow!(
+            Err(e) => Err(Error::Login(format!(
                 "Async challenge channel closed unexpectedly or sender dropped: {}",
                 e
-            )),
+            ))),
         }
     }
 
@@ -71,3 +79,62 @@ impl<T: Send + 'static> Default for AsyncChallengeState<T> {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reject_second_request_while_pending() {
+        let state = AsyncChallengeState::<String>::new();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let first = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let ready = |_| async {
+                    let _ = ready_tx.send(());
+                };
+                state.request_solve("first".to_string(), ready).await
+            }
+        });
+        ready_rx.await.unwrap();
+
+        let second = state
+            .request_solve("second".to_string(), |_| async {})
+            .await;
+        assert!(matches!(second, Err(Error::Login(_))));
+
+        state.solve("1234".to_string()).await;
+        let first = first.await.unwrap().unwrap();
+        assert!(matches!(first, ChallengeSolution::Solved(s) if s == "1234"));
+    }
+
+    #[tokio::test]
+    async fn replace_abandoned_request() {
+        let state = AsyncChallengeState::<String>::new();
+        let abandoned = tokio::spawn({
+            let state = state.clone();
+            async move { state.request_solve("first".to_string(), |_| async {}).await }
+        });
+        while state.mutex.lock().unwrap().is_none() {
+            tokio::task::yield_now().await;
+        }
+        abandoned.abort();
+        let _ = abandoned.await;
+
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let second = tokio::spawn({
+            let state = state.clone();
+            async move {
+                let ready = |_| async {
+                    let _ = ready_tx.send(());
+                };
+                state.request_solve("second".to_string(), ready).await
+            }
+        });
+        ready_rx.await.unwrap();
+        state.cancel().await;
+        let second = second.await.unwrap().unwrap();
+        assert!(matches!(second, ChallengeSolution::Cancel));
+    }
+}
@@ -0,0 +1,301 @@
+//! One miIO call sent to many devices at once.
+
+use std::future::Future;
+
+use futures_util::stream::{self, StreamExt};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Device, Error, MiCloudProtocol, Result};
+
+/// Requests in flight at once when the caller does not say otherwise
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 8;
+
+/// Which devices a batch call goes to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "by", rename_all = "kebab-case")]
+pub enum DeviceSelector {
+    Dids {
+        dids: Vec<String>,
+    },
+    /// `*` matches any run of characters, `?` a single one, e.g. `yeelink.light.*`
+    Model {
+        pattern: String,
+    },
+    /// Every device of a home, or of one of its rooms. Unknown ids are an error.
+    Home {
+        home_id: String,
+        #[serde(default)]
+        room_id: Option<String>,
+    },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum BatchOutcome {
+    Ok { result: Value },
+    Failed { error: Error },
+}
+
+#[derive(Serialize, Debug)]
+pub struct BatchResult {
+    pub did: String,
+    /// Empty for dids the device list does not contain
+    pub name: String,
+    pub model: String,
+    #[serde(flatten)]
+    pub outcome: BatchOutcome,
+}
+
+/// Per-device results, in the order of the device list.
+#[derive(Serialize, Debug, Default)]
+pub struct BatchReport {
+    pub results: Vec<BatchResult>,
+}
+
+impl BatchReport {
+    pub fn succeeded(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|r| matches!(r.outcome, BatchOutcome::Ok { .. }))
+            .count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.succeeded()
+    }
+}
+
+fn glob_regex(pattern: &str) -> Regex {
+    let re = regex::escape(pattern)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    Regex::new(&format!("^{}$", re)).expect("escaped glob is a valid regex")
+}
+
+impl MiCloudProtocol {
+    /// Devices `selector` points at, each once.
+    pub async fn select_devices(
+        &self,
+        selector: &DeviceSelector,
+        country: Option<&str>,
+    ) -> Result<Vec<Device>> {
+        match selector {
+            DeviceSelector::Dids { dids } => {
+                let ids: Vec<&str> = dids.iter().map(String::as_str).collect();
+                let mut listed = self.get_devices(Some(&ids), country).await?;
+                // Unlisted dids are still called, the cloud has the last word
+                let mut devices = vec![];
+                for did in dids {
+                    if devices.iter().any(|d: &Device| &d.did == did) {
+                        continue;
+                    }
+                    let device = match listed.iter().position(|d| &d.did == did) {
+                        Some(i) => listed.swap_remove(i),
+                        None => Device {
+                            did: did.clone(),
+                            ..Default::default()
+                        },
+                    };
+                    devices.push(device);
+                }
+                Ok(devices)
+            }
+            DeviceSelector::Model { pattern } => {
+                let re = glob_regex(pattern);
+                let mut devices = self.get_devices(None, country).await?;
+                devices.retain(|d| re.is_match(&d.model));
+                Ok(devices)
+            }
+            DeviceSelector::Home { home_id, room_id } => {
+                let homes = self.get_homes(country).await?;
+                let home = homes
+                    .iter()
+                    .find(|h| &h.id == home_id)
+                    .ok_or_else(|| Error::Decode(format!("Unknown home {}", home_id)))?;
+                let dids: Vec<&str> = match room_id {
+                    Some(room_id) => {
+                        let room =
+                            home.rooms
+                                .iter()
+                                .find(|r| &r.id == room_id)
+                                .ok_or_else(|| {
+                                    Error::Decode(format!(
+                                        "Unknown room {} in home {}",
+                                        room_id, home_id
+                                    ))
+                                })?;
+                        room.dids.iter().map(String::as_str).collect()
+                    }
+                    None => home.all_dids().collect(),
+                };
+                let mut devices = self.get_devices(None, country).await?;
+                devices.retain(|d| dids.contains(&d.did.as_str()));
+                Ok(devices)
+            }
+        }
+    }
+
+    /// Sends `method` with `params` to every selected device, at most
+    /// `concurrency` at a time. Failures are reported per device, only
+    /// resolving the selector can fail the whole call.
+    pub async fn call_devices(
+        &self,
+        selector: &DeviceSelector,
+        method: &str,
+        params: Option<Value>,
+        concurrency: usize,
+        country: Option<&str>,
+    ) -> Result<BatchReport> {
+        let devices = self.select_devices(selector, country).await?;
+        Ok(fan_out(devices, concurrency, |did| {
+            let params = params.clone();
+            async move { self.call_device(&did, method, params, country).await }
+        })
+        .await)
+    }
+}
+
+/// Runs `call` with the did of every device, at most `concurrency` at a time,
+/// and reports the results in the order of `devices`.
+pub(crate) async fn fan_out<F, Fut>(
+    devices: Vec<Device>,
+    concurrency: usize,
+    call: F,
+) -> BatchReport
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Value>>,
+{
+    let results = stream::iter(devices)
+        .map(|device| {
+            let call = call(device.did.clone());
+            async move {
+                let outcome = match call.await {
+                    Ok(result) => BatchOutcome::Ok { result },
+                    Err(error) => BatchOutcome::Failed { error },
+                };
+                BatchResult {
+                    did: device.did,
+                    name: device.name,
+                    model: device.model,
+                    outcome,
+                }
+            }
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await;
+    BatchReport { results }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{device_json, logged_in};
+    use miio_mock::{MockAccount, MockCloud};
+    use serde_json::json;
+
+    #[test]
+    fn glob() {
+        let re = glob_regex("yeelink.light.*");
+        assert!(re.is_match("yeelink.light.color1"));
+        assert!(!re.is_match("yeelinkXlight.color1"));
+        assert!(!re.is_match("zhimi.yeelink.light.x"));
+        assert!(glob_regex("lumi.sensor_?t.v1").is_match("lumi.sensor_ht.v1"));
+    }
+
+    #[tokio::test]
+    async fn call_selected_devices() {
+        let device = |did: &str, model: &str| {
+            device_json(
+                did,
+                json!({ "model": model, "name": format!("Device {}", did) }),
+            )
+        };
+        let mock = MockCloud::start(MockAccount::default()).await;
+        {
+            let mut state = mock.state();
+            state.devices = vec![
+                device("1", "yeelink.light.color1"),
+                device("2", "yeelink.light.mono1"),
+                device("3", "yeelink.light.ceiling4"),
+                device("4", "zhimi.airpurifier.m1"),
+            ];
+            for did in ["1", "2"] {
+                state.responses.insert(
+                    format!("/home/rpc/{}", did),
+                    json!({ "code": 0, "message": "ok", "result": ["ok"] }),
+                );
+            }
+        }
+        let mi = logged_in(&mock).await;
+
+        let selector = DeviceSelector::Model {
+            pattern: "yeelink.light.*".to_string(),
+        };
+        let params = Some(json!(["cfg_lan_ctrl", "1"]));
+        let report = mi
+            .call_devices(&selector, "set_ps", params.clone(), 2, None)
+            .await
+            .unwrap();
+        let dids: Vec<_> = report.results.iter().map(|r| r.did.as_str()).collect();
+        assert_eq!(dids, ["1", "2", "3"]);
+        assert_eq!((report.succeeded(), report.failed()), (2, 1));
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(value["results"][0]["status"], "ok");
+        assert_eq!(value["results"][0]["name"], "Device 1");
+        assert_eq!(value["results"][2]["status"], "failed");
+
+        let selector: DeviceSelector =
+            serde_json::from_value(json!({ "by": "dids", "dids": ["2", "9", "2"] })).unwrap();
+        let report = mi
+            .call_devices(&selector, "set_ps", params, 8, None)
+            .await
+            .unwrap();
+        let dids: Vec<_> = report.results.iter().map(|r| r.did.as_str()).collect();
+        assert_eq!(dids, ["2", "9"]);
+        assert_eq!(report.results[1].name, "");
+        assert_eq!(report.failed(), 1);
+    }
+
+    #[tokio::test]
+    async fn select_home_and_room() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        {
+            let mut state = mock.state();
+            state.devices = vec![
+                device_json("1", json!({})),
+                device_json("2", json!({})),
+                device_json("3", json!({})),
+            ];
+            state.responses.insert(
+                "/v2/homeroom/gethome".to_string(),
+                json!({ "code": 0, "result": { "homelist": [{
+                    "id": "10", "name": "Flat", "dids": ["1"],
+                    "roomlist": [{ "id": "20", "name": "Kitchen", "dids": ["2"] }]
+                }] } }),
+            );
+        }
+        let mi = logged_in(&mock).await;
+        let select = |home_id: &str, room_id: Option<&str>| DeviceSelector::Home {
+            home_id: home_id.to_string(),
+            room_id: room_id.map(str::to_string),
+        };
+
+        let devices = mi.select_devices(&select("10", None), None).await.unwrap();
+        let dids: Vec<_> = devices.iter().map(|d| d.did.as_str()).collect();
+        assert_eq!(dids, ["1", "2"]);
+        let devices = mi
+            .select_devices(&select("10", Some("20")), None)
+            .await
+            .unwrap();
+        assert_eq!(devices[0].did, "2");
+
+        let err = mi.select_devices(&select("11", None), None).await;
+        assert_eq!(err.unwrap_err().to_string(), "Unknown home 11");
+        let err = mi.select_devices(&select("10", Some("21")), None).await;
+        assert_eq!(err.unwrap_err().to_string(), "Unknown room 21 in home 10");
+    }
+}
@@ -0,0 +1,91 @@
+//! Bind keys of Bluetooth devices, which have no miIO token.
+
+use serde_json::{json, Value};
+
+use crate::{cloud_error, Device, Error, MiCloudProtocol, Result, Transport};
+
+fn parse_beacon_key(res: &Value) -> Result<String> {
+    match res["result"]["beaconkey"].as_str() {
+        Some(key) => Ok(key.to_string()),
+        None => Err(cloud_error(res, "Get BLE beacon key failed")),
+    }
+}
+
+impl MiCloudProtocol {
+    /// Fetches the beacon (bind) key of a BLE device, e.g. `blt.3.xxx`.
+    pub async fn get_ble_beacon_key(&self, did: &str, country: Option<&str>) -> Result<String> {
+        let country = country.unwrap_or(self.country.as_str());
+        let res = self
+            .request_with(
+                Transport::Rc4,
+                "/v2/device/blt_get_beaconkey",
+                json!({ "did": did, "pdid": 1 }),
+                country,
+            )
+            .await?;
+        parse_beacon_key(&res)
+    }
+
+    /// Stores the beacon key of every BLE device in `devices` in `Device::ble_key`.
+    /// Devices the cloud has no key for are skipped, other errors are returned.
+    pub async fn fill_ble_beacon_keys(
+        &self,
+        devices: &mut [Device],
+        country: Option<&str>,
+    ) -> Result<()> {
+        for device in devices.iter_mut().filter(|d| d.is_ble()) {
+            match self.get_ble_beacon_key(&device.did, country).await {
+                Ok(key) => device.ble_key = Some(key),
+                Err(Error::CloudError { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{device, logged_in};
+    use miio_mock::{MockAccount, MockCloud};
+
+    #[test]
+    fn parse_beacon_key_response() {
+        let res = json!({
+            "code": 0,
+            "message": "ok",
+            "result": { "beaconkey": "00112233445566778899aabbccddeeff", "pdid": 1 }
+        });
+        assert_eq!(
+            parse_beacon_key(&res).unwrap(),
+            "00112233445566778899aabbccddeeff"
+        );
+        let err = parse_beacon_key(&json!({ "code": -6, "message": "device not found" }));
+        assert!(matches!(err, Err(Error::CloudError { code: -6, .. })));
+    }
+
+    #[tokio::test]
+    async fn fill_keys_of_ble_devices() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        mock.state().beacon_keys.insert(
+            "blt.3.1".to_string(),
+            "00112233445566778899aabbccddeeff".to_string(),
+        );
+        let mi = logged_in(&mock).await;
+
+        let sensor = |did: &str| {
+            device(
+                did,
+                json!({ "model": "miaomiaoce.sensor_ht.t2", "pid": "6" }),
+            )
+        };
+        let mut devices = vec![sensor("blt.3.1"), sensor("blt.3.2"), sensor("123")];
+        mi.fill_ble_beacon_keys(&mut devices, None).await.unwrap();
+
+        let keys: Vec<_> = devices.iter().map(|d| d.ble_key()).collect();
+        assert_eq!(keys, [Some("00112233445566778899aabbccddeeff"), None, None]);
+        let paths = mock.state().paths().join(",");
+        assert_eq!(paths.matches("blt_get_beaconkey").count(), 2);
+    }
+}
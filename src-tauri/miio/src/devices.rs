@@ -0,0 +1,279 @@
+//! Device listing beyond the primary `/home/device_list`: shared devices,
+//! virtual models, Huami wearables and devices of every home.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{cloud_error, Device, MiCloudProtocol, Result, Transport};
+
+/// Devices per `/v2/home/home_device_list` request
+const HOME_DEVICES_PAGE_SIZE: u64 = 200;
+
+/// Whether the account owns a device or another account shared it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Ownership {
+    /// `shareFlag` 0, `permitLevel` 16
+    #[default]
+    Owner,
+    /// `shareFlag` 1, `permitLevel` 36 for full control
+    Shared,
+}
+
+impl Ownership {
+    pub(crate) fn from_flags(share_flag: i64, permit_level: i64) -> Self {
+        if share_flag == 1 || permit_level == 36 {
+            Ownership::Shared
+        } else {
+            Ownership::Owner
+        }
+    }
+}
+
+/// What `list_devices` returns besides the owner's primary device list.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct DeviceListOptions {
+    /// Devices of homes other accounts shared with this one. Devices shared
+    /// individually are part of the primary list either way.
+    pub shared: bool,
+    /// Virtual models such as light groups
+    pub virtual_models: bool,
+    /// Huami (Amazfit, Mi Band) wearables
+    pub huami: bool,
+    /// Devices of every home, not only the primary one
+    pub all_homes: bool,
+    /// Only list the devices of these homes, see `Home::id`
+    pub home_ids: Vec<String>,
+    /// Fetch beacon keys of BLE devices, one request per device
+    pub ble_keys: bool,
+}
+
+#[derive(Deserialize)]
+struct SharedFamily {
+    #[serde(deserialize_with = "crate::lenient::string")]
+    home_id: String,
+    home_owner: u64,
+}
+
+fn parse_list(res: &Value, key: &str, fallback_msg: &str) -> Result<Vec<Device>> {
+    if res["result"].is_null() {
+        return Err(cloud_error(res, fallback_msg));
+    }
+    let mut devices: Vec<Device> = match res["result"][key].as_array() {
+        Some(list) => serde_json::from_value(Value::Array(list.clone()))?,
+        None => vec![],
+    };
+    devices.iter_mut().for_each(Device::update_ownership);
+    Ok(devices)
+}
+
+impl MiCloudProtocol {
+    /// Lists devices according to `options`, each device once.
+    pub async fn list_devices(
+        &self,
+        options: &DeviceListOptions,
+        country: Option<&str>,
+    ) -> Result<Vec<Device>> {
+        let country = country.unwrap_or(self.country.as_str());
+        let mut devices = vec![];
+        if options.home_ids.is_empty() {
+            let req = json!({
+                "getVirtualModel": options.virtual_models,
+                "getHuamiDevices": options.huami as u8,
+                "get_split_device": false,
+                "support_smart_home": true,
+            });
+            let res = self.request("/home/device_list", req, country).await?;
+            devices = parse_list(&res, "list", "Get devices failed")?;
+        }
+
+        // (home id, owner uid)
+        let mut homes: Vec<(String, u64)> = vec![];
+        if options.all_homes || !options.home_ids.is_empty() {
+            let user_id = self.user_id().and_then(|id| id.parse().ok()).unwrap_or(0);
+            for home in self.get_homes(Some(country)).await? {
+                let owner = if home.uid == 0 { user_id } else { home.uid };
+                homes.push((home.id, owner));
+            }
+        }
+        if options.shared {
+            homes.extend(self.shared_homes(country).await?);
+        }
+        if !options.home_ids.is_empty() {
+            homes.retain(|(id, _)| options.home_ids.contains(id));
+        }
+        for (home_id, owner) in homes {
+            for device in self.home_devices(&home_id, owner, country).await? {
+                if !devices.iter().any(|d| d.did == device.did) {
+                    devices.push(device);
+                }
+            }
+        }
+
+        if options.ble_keys {
+            self.fill_ble_beacon_keys(&mut devices, Some(country))
+                .await?;
+        }
+        Ok(devices)
+    }
+
+    /// Homes other accounts shared with this one, as (home id, owner uid).
+    async fn shared_homes(&self, country: &str) -> Result<Vec<(String, u64)>> {
+        let req = json!({ "fetch_own": true, "fetch_share": true });
+        let res = self
+            .request_with(Transport::Rc4, "/v2/user/get_device_cnt", req, country)
+            .await?;
+        if res["result"].is_null() {
+            return Err(cloud_error(&res, "Get shared homes failed"));
+        }
+        let families: Vec<SharedFamily> = match res["result"]["share"]["share_family"].as_array() {
+            Some(list) => serde_json::from_value(Value::Array(list.clone()))?,
+            None => vec![],
+        };
+        Ok(families
+            .into_iter()
+            .map(|f| (f.home_id, f.home_owner))
+            .collect())
+    }
+
+    /// Devices of a home, following `next_start_did` until `has_more` is false.
+    async fn home_devices(&self, home_id: &str, owner: u64, country: &str) -> Result<Vec<Device>> {
+        let mut devices = vec![];
+        let mut start_did: Option<String> = None;
+        loop {
+            let mut req = json!({
+                "home_owner": owner,
+                "home_id": home_id.parse::<u64>().map(Value::from).unwrap_or(json!(home_id)),
+                "limit": HOME_DEVICES_PAGE_SIZE,
+                "get_split_device": true,
+                "support_smart_home": true,
+            });
+            if let Some(did) = &start_did {
+                req["start_did"] = json!(did);
+            }
+            let res = self
+                .request_with(Transport::Rc4, "/v2/home/home_device_list", req, country)
+                .await?;
+            devices.extend(parse_list(&res, "device_info", "Get home devices failed")?);
+
+            let next = res["result"]["next_start_did"].as_str().unwrap_or_default();
+            // An empty or repeated cursor would request the same page forever
+            if res["result"]["has_more"] != true
+                || next.is_empty()
+                || start_did.as_deref() == Some(next)
+            {
+                return Ok(devices);
+            }
+            start_did = Some(next.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{device_json, logged_in};
+    use miio_mock::{MockAccount, MockCloud};
+
+    fn device(did: &str, share_flag: i64, permit_level: i64) -> Value {
+        device_json(
+            did,
+            json!({ "shareFlag": share_flag, "permitLevel": permit_level }),
+        )
+    }
+
+    fn dids(devices: &[Device]) -> Vec<&str> {
+        devices.iter().map(|d| d.did.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn list_shared_and_home_devices() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        {
+            let mut state = mock.state();
+            state.devices = vec![device("1", 0, 16), device("2", 1, 36)];
+            state.responses.insert(
+                "/v2/homeroom/gethome".to_string(),
+                json!({ "code": 0, "result": { "homelist": [
+                    { "id": "10", "name": "Flat", "uid": 1234567890 },
+                    { "id": "11", "name": "Cottage", "uid": 1234567890 }
+                ] } }),
+            );
+            state.responses.insert(
+                "/v2/user/get_device_cnt".to_string(),
+                json!({ "code": 0, "result": { "share": { "share_family": [
+                    { "home_id": 20, "home_owner": 42 }
+                ] } } }),
+            );
+            state
+                .home_devices
+                .insert("10".to_string(), vec![device("1", 0, 16)]);
+            state.home_devices.insert(
+                "11".to_string(),
+                vec![device("3", 0, 16), device("5", 0, 16), device("6", 0, 16)],
+            );
+            state.home_page_size = Some(2);
+            state
+                .home_devices
+                .insert("20".to_string(), vec![device("4", 1, 36)]);
+        }
+        let mi = logged_in(&mock).await;
+
+        let devices = mi
+            .list_devices(&DeviceListOptions::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(dids(&devices), ["1", "2"]);
+
+        let options = DeviceListOptions {
+            shared: true,
+            virtual_models: true,
+            huami: true,
+            all_homes: true,
+            ..Default::default()
+        };
+        let devices = mi.list_devices(&options, None).await.unwrap();
+        assert_eq!(dids(&devices), ["1", "2", "3", "5", "6", "4"]);
+        assert_eq!(devices[0].ownership(), Ownership::Owner);
+        assert_eq!(devices[5].ownership(), Ownership::Shared);
+        let list_request = mock
+            .state()
+            .requests
+            .iter()
+            .rev()
+            .find(|r| r.path == "/app/home/device_list")
+            .and_then(|r| r.data.clone())
+            .unwrap();
+        assert_eq!(list_request["getVirtualModel"], true);
+        assert_eq!(list_request["getHuamiDevices"], 1);
+
+        let options = DeviceListOptions {
+            home_ids: vec!["11".to_string()],
+            ..Default::default()
+        };
+        let devices = mi.list_devices(&options, None).await.unwrap();
+        assert_eq!(dids(&devices), ["3", "5", "6"]);
+        let pages: Vec<_> = mock
+            .state()
+            .requests
+            .iter()
+            .filter(|r| r.path == "/app/v2/home/home_device_list")
+            .filter_map(|r| r.data.clone())
+            .filter(|data| data["home_id"] == 11)
+            .map(|data| data["start_did"].clone())
+            .collect();
+        assert_eq!(pages[pages.len() - 2..], [Value::Null, json!("6")]);
+    }
+
+    #[test]
+    fn ownership_from_flags() {
+        assert_eq!(Ownership::from_flags(0, 16), Ownership::Owner);
+        assert_eq!(Ownership::from_flags(1, 36), Ownership::Shared);
+        assert_eq!(Ownership::from_flags(0, 36), Ownership::Shared);
+        assert_eq!(
+            serde_json::to_value(Ownership::Shared).unwrap(),
+            json!("shared")
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! LAN discovery of miIO devices by broadcasting the hello packet.
+
+use log::debug;
+use serde::Serialize;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    lan::{hello_packet, Header, MIIO_PORT},
+    Device, Result,
+};
+
+/// A device that answered the hello broadcast.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredDevice {
+    pub device_id: u32,
+    pub stamp: u32,
+    pub addr: SocketAddr,
+}
+
+impl DiscoveredDevice {
+    /// Device id in the same format as the cloud `did`.
+    pub fn did(&self) -> String {
+        self.device_id.to_string()
+    }
+}
+
+/// Cloud device joined with the discovery result.
+#[derive(Serialize, Debug)]
+pub struct DeviceReachability {
+    pub device: Device,
+    /// Address the device answered from, `None` if it is cloud-only.
+    pub local_ip: Option<IpAddr>,
+    /// `true` when the device answered from an address other than its cloud `localip`.
+    pub localip_changed: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DiscoveryReport {
+    pub devices: Vec<DeviceReachability>,
+    /// Devices that answered but are not in the cloud list.
+    pub unknown: Vec<DiscoveredDevice>,
+}
+
+/// Broadcasts hello to `255.255.255.255:54321` and collects replies until `wait` elapses.
+pub async fn discover(wait: Duration) -> Result<Vec<DiscoveredDevice>> {
+    discover_on(SocketAddr::from(([255, 255, 255, 255], MIIO_PORT)), wait).await
+}
+
+/// Sends hello to `target`, which can be a subnet broadcast or a single host.
+pub async fn discover_on(target: SocketAddr, wait: Duration) -> Result<Vec<DiscoveredDevice>> {
+    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&hello_packet(), target).await?;
+
+    let deadline = Instant::now() + wait;
+    let mut found: Vec<DiscoveredDevice> = vec![];
+    let mut buf = [0u8; 1024];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, addr) = received?;
+        let header = match Header::parse(&buf[..len]) {
+            Ok(header) => header,
+            Err(e) => {
+                debug!("[miio::discovery] ignoring reply from {}: {}", addr, e);
+                continue;
+            }
+        };
+        if !found.iter().any(|d| d.device_id == header.device_id) {
+            found.push(DiscoveredDevice {
+                device_id: header.device_id,
+                stamp: header.stamp,
+                addr,
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// Joins the cloud device list with discovered devices by `did`.
+pub fn match_devices(devices: Vec<Device>, discovered: &[DiscoveredDevice]) -> DiscoveryReport {
+    let devices = devices
+        .into_iter()
+        .map(|device| {
+            let local_ip = discovered
+                .iter()
+                .find(|d| d.did() == device.did)
+                .map(|d| d.addr.ip());
+            let localip_changed =
+                local_ip.is_some_and(|ip| device.localip.parse::<IpAddr>().ok() != Some(ip));
+            DeviceReachability {
+                device,
+                local_ip,
+                localip_changed,
+            }
+        })
+        .collect::<Vec<_>>();
+    let unknown = discovered
+        .iter()
+        .filter(|d| !devices.iter().any(|r| r.device.did == d.did()))
+        .cloned()
+        .collect();
+    DiscoveryReport { devices, unknown }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::device;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn discover_fake_device() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(buf[..len], hello_packet());
+            let reply = Header {
+                length: 32,
+                unknown: 0,
+                device_id: 123456,
+                stamp: 77,
+                checksum: [0xFF; 16],
+            };
+            // Devices may answer more than once
+            for _ in 0..2 {
+                socket.send_to(&reply.to_bytes(), client).await.unwrap();
+            }
+        });
+
+        let found = discover_on(addr, Duration::from_millis(300)).await.unwrap();
+        assert_eq!(
+            found,
+            vec![DiscoveredDevice {
+                device_id: 123456,
+                stamp: 77,
+                addr,
+            }]
+        );
+    }
+
+    #[test]
+    fn match_cloud_devices() {
+        let discovered = vec![
+            DiscoveredDevice {
+                device_id: 1,
+                stamp: 0,
+                addr: "192.168.1.20:54321".parse().unwrap(),
+            },
+            DiscoveredDevice {
+                device_id: 2,
+                stamp: 0,
+                addr: "192.168.1.30:54321".parse().unwrap(),
+            },
+            DiscoveredDevice {
+                device_id: 9,
+                stamp: 0,
+                addr: "192.168.1.90:54321".parse().unwrap(),
+            },
+        ];
+        let devices = vec![
+            device("1", json!({ "localip": "192.168.1.20" })),
+            device("2", json!({ "localip": "192.168.1.3" })),
+            device("3", json!({ "localip": "192.168.1.4" })),
+        ];
+        let report = match_devices(devices, &discovered);
+
+        let summary: Vec<_> = report
+            .devices
+            .iter()
+            .map(|r| (r.device.did.as_str(), r.local_ip, r.localip_changed))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("1", Some("192.168.1.20".parse().unwrap()), false),
+                ("2", Some("192.168.1.30".parse().unwrap()), true),
+                ("3", None, false),
+            ]
+        );
+        assert_eq!(report.unknown, vec![discovered[2].clone()]);
+    }
+}
@@ -0,0 +1,159 @@
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::fmt::Display;
+
+use crate::login_codes;
+
+/// Errors returned by the `miio` crate.
+///
+/// Serializes to `{ "kind": "...", "message": "..." }` plus `code`/`status`
+/// when present, so UI code can react to each kind.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Request error: Not logged in")]
+    NotLoggedIn,
+    #[error("Captcha cancelled by user")]
+    CaptchaCancelled,
+    #[error("2FA challenge was canceled by the user")]
+    TwoFactorCancelled,
+    #[error("{}", login_codes::error_message(*.0).unwrap_or("Login failed: Invalid credentials."))]
+    InvalidCredentials(i64),
+    /// The session was rejected and no password is stored to log in again
+    #[error("Session expired, please log in again")]
+    SessionExpired,
+    #[error("Unknown account {0}")]
+    UnknownAccount(String),
+    #[error("Request error: Server Location {0} is not supported")]
+    UnsupportedCountry(String),
+    #[error("HTTP {0}")]
+    Http(u16),
+    #[error("{message}")]
+    CloudError { code: i64, message: String },
+    /// Error reported by a device over the local miIO protocol
+    #[error("{message}")]
+    DeviceError { code: i64, message: String },
+    #[error("No released MIoT spec found for model {0}")]
+    UnknownModel(String),
+    #[error("{model} does not support {feature}")]
+    Unsupported {
+        model: String,
+        feature: &'static str,
+    },
+    /// The device accepted a change but reads back the old value
+    #[error("{0}")]
+    NotApplied(String),
+    #[error("{0}")]
+    Decode(String),
+    /// Unexpected response during the login flow
+    #[error("{0}")]
+    Login(String),
+    #[error("{0}")]
+    Network(String),
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{0}")]
+    Io(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub(crate) fn decode(message: impl Display) -> Self {
+        Error::Decode(message.to_string())
+    }
+
+    pub(crate) fn login(message: impl Display) -> Self {
+        Error::Login(message.to_string())
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::NotLoggedIn => "NotLoggedIn",
+            Error::CaptchaCancelled => "CaptchaCancelled",
+            Error::TwoFactorCancelled => "TwoFactorCancelled",
+            Error::InvalidCredentials(_) => "InvalidCredentials",
+            Error::SessionExpired => "SessionExpired",
+            Error::UnknownAccount(_) => "UnknownAccount",
+            Error::UnsupportedCountry(_) => "UnsupportedCountry",
+            Error::Http(_) => "Http",
+            Error::CloudError { .. } => "CloudError",
+            Error::DeviceError { .. } => "DeviceError",
+            Error::UnknownModel(_) => "UnknownModel",
+            Error::Unsupported { .. } => "Unsupported",
+            Error::NotApplied(_) => "NotApplied",
+            Error::Decode(_) => "Decode",
+            Error::Login(_) => "Login",
+            Error::Network(_) => "Network",
+            Error::Timeout(_) => "Timeout",
+            Error::Io(_) => "Io",
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        if let Some(status) = e.status() {
+            Error::Http(status.as_u16())
+        } else if e.is_decode() {
+            Error::Decode(e.to_string())
+        } else if e.is_timeout() {
+            Error::Timeout(e.to_string())
+        } else {
+            Error::Network(e.to_string())
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e.to_string())
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Error", 4)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.to_string())?;
+        match self {
+            Error::InvalidCredentials(code)
+            | Error::CloudError { code, .. }
+            | Error::DeviceError { code, .. } => state.serialize_field("code", code)?,
+            Error::Http(status) => state.serialize_field("status", status)?,
+            _ => {}
+        }
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn serialize() {
+        assert_eq!(
+            serde_json::to_value(Error::InvalidCredentials(login_codes::INVALID_CREDENTIAL))
+                .unwrap(),
+            json!({
+                "kind": "InvalidCredentials",
+                "message": "Login failed: Incorrect password. Please verify your credentials.",
+                "code": 70002
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(Error::Http(401)).unwrap(),
+            json!({ "kind": "Http", "message": "HTTP 401", "status": 401 })
+        );
+        assert_eq!(
+            serde_json::to_value(Error::NotLoggedIn).unwrap(),
+            json!({ "kind": "NotLoggedIn", "message": "Request error: Not logged in" })
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Device token export in formats understood by other tools.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{fmt::Write, str::FromStr};
+
+use crate::{Device, Error, Result};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportFormat {
+    /// `configuration.yaml` entries for the `xiaomi_miio` integration, BLE
+    /// bind keys for `xiaomi_ble` as comments
+    HomeAssistant,
+    /// JSON list with python-miio `CloudDeviceInfo` field names plus `ble_key`
+    PythonMiio,
+    /// `name,model,did,ip,token,mac,ble_key`
+    Csv,
+    /// The table printed by `miio-cli devices`
+    MiioCli,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 4] = [
+        ExportFormat::HomeAssistant,
+        ExportFormat::PythonMiio,
+        ExportFormat::Csv,
+        ExportFormat::MiioCli,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportFormat::HomeAssistant => "home-assistant",
+            ExportFormat::PythonMiio => "python-miio",
+            ExportFormat::Csv => "csv",
+            ExportFormat::MiioCli => "miio-cli",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::HomeAssistant => "yaml",
+            ExportFormat::PythonMiio => "json",
+            ExportFormat::Csv => "csv",
+            ExportFormat::MiioCli => "txt",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|f| f.name() == s)
+            .ok_or_else(|| Error::decode(format!("Unknown export format {s}")))
+    }
+}
+
+/// Renders `devices` in `format`. Home Assistant and python-miio exports skip
+/// devices with neither a token and a local IP nor a BLE key, since they cannot
+/// be used locally.
+pub fn export_devices(devices: &[Device], format: ExportFormat) -> Result<String> {
+    Ok(match format {
+        ExportFormat::HomeAssistant => home_assistant(devices),
+        ExportFormat::PythonMiio => {
+            let list: Vec<_> = devices
+                .iter()
+                .filter(|d| has_local_token(d) || d.ble_key.is_some())
+                .map(|d| {
+                    let mut info = json!({
+                        "ip": d.localip,
+                        "token": d.token,
+                        "did": d.did,
+                        "mac": d.mac,
+                        "name": d.name,
+                        "model": d.model,
+                        "description": d.desc,
+                        "parent_id": d.parent_id,
+                        "parent_model": d.parent_model,
+                        "is_online": d.is_online,
+                    });
+                    if let Some(key) = &d.ble_key {
+                        info["ble_key"] = json!(key);
+                    }
+                    info
+                })
+                .collect();
+            serde_json::to_string_pretty(&list)? + "\n"
+        }
+        ExportFormat::Csv => csv(devices),
+        ExportFormat::MiioCli => miio_cli_table(devices),
+    })
+}
+
+fn has_local_token(device: &Device) -> bool {
+    !device.token.is_empty() && !device.localip.is_empty()
+}
+
+fn home_assistant(devices: &[Device]) -> String {
+    let mut out = String::new();
+    for d in devices.iter().filter(|d| has_local_token(d)) {
+        let _ = write!(
+            out,
+            "- platform: xiaomi_miio\n  name: {}\n  host: {}\n  token: {}\n  model: {}\n",
+            yaml_string(&d.name),
+            d.localip,
+            d.token,
+            d.model
+        );
+    }
+    for d in devices {
+        if !d.token.is_empty() && d.localip.is_empty() {
+            let _ = writeln!(out, "# {} ({}) skipped, no local IP", d.name, d.model);
+        }
+        if let Some(key) = &d.ble_key {
+            let _ = writeln!(out, "# {} ({}) bind key: {}", d.name, d.model, key);
+        }
+    }
+    out
+}
+
+fn yaml_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn csv(devices: &[Device]) -> String {
+    let mut out = String::from("name,model,did,ip,token,mac,ble_key\n");
+    for d in devices {
+        let ble_key = d.ble_key.as_deref().unwrap_or_default();
+        let row: Vec<_> = [&d.name, &d.model, &d.did, &d.localip, &d.token, &d.mac]
+            .into_iter()
+            .map(String::as_str)
+            .chain([ble_key])
+            .map(csv_cell)
+            .collect();
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+fn csv_cell(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn miio_cli_table(devices: &[Device]) -> String {
+    const COLUMNS: [&str; 6] = ["did", "model", "name", "localip", "token", "ble_key"];
+    let rows: Vec<[&str; 6]> = devices
+        .iter()
+        .map(|d| {
+            [
+                d.did.as_str(),
+                &d.model,
+                &d.name,
+                &d.localip,
+                &d.token,
+                d.ble_key.as_deref().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    let widths: Vec<usize> = (0..COLUMNS.len())
+        .map(|i| {
+            rows.iter()
+                .map(|r| r[i].chars().count())
+                .fold(COLUMNS[i].len(), usize::max)
+        })
+        .collect();
+    let mut out = String::new();
+    for row in std::iter::once(COLUMNS).chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn device(did: &str, name: &str, token: &str) -> Device {
+        test_util::device(
+            did,
+            json!({ "mac": "AA:BB:CC:DD:EE:FF", "name": name, "token": token }),
+        )
+    }
+
+    fn devices() -> Vec<Device> {
+        let mut sensor = device("blt.3.1", "Sensor, hall", "");
+        sensor.ble_key = Some("ffeeddccbbaa99887766554433221100".to_string());
+        vec![
+            device("1", "Desk \"lamp\"", "00112233445566778899aabbccddeeff"),
+            sensor,
+            device("blt.3.2", "Unbound sensor", ""),
+        ]
+    }
+
+    fn with_unreachable() -> Vec<Device> {
+        let mut devices = devices();
+        let mut plug = device("4", "Plug", "ffeeddccbbaa99887766554433221100");
+        plug.localip = String::new();
+        devices.push(plug);
+        devices
+    }
+
+    #[test]
+    fn home_assistant() {
+        assert_eq!(
+            export_devices(&with_unreachable(), ExportFormat::HomeAssistant).unwrap(),
+            "- platform: xiaomi_miio\n  name: \"Desk \\\"lamp\\\"\"\n  host: 192.168.1.20\n  token: 00112233445566778899aabbccddeeff\n  model: yeelink.light.color1\n\
+             # Sensor, hall (yeelink.light.color1) bind key: ffeeddccbbaa99887766554433221100\n\
+             # Plug (yeelink.light.color1) skipped, no local IP\n"
+        );
+    }
+
+    #[test]
+    fn python_miio() {
+        let out = export_devices(&with_unreachable(), ExportFormat::PythonMiio).unwrap();
+        let list: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(list.as_array().unwrap().len(), 2);
+        assert_eq!(list[0]["ip"], "192.168.1.20");
+        assert_eq!(list[0]["token"], "00112233445566778899aabbccddeeff");
+        assert_eq!(list[0]["is_online"], true);
+        assert_eq!(list[0].get("ble_key"), None);
+        assert_eq!(list[1]["ble_key"], "ffeeddccbbaa99887766554433221100");
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(
+            export_devices(&devices(), ExportFormat::Csv).unwrap(),
+            "name,model,did,ip,token,mac,ble_key\n\
+             \"Desk \"\"lamp\"\"\",yeelink.light.color1,1,192.168.1.20,00112233445566778899aabbccddeeff,AA:BB:CC:DD:EE:FF,\n\
+             \"Sensor, hall\",yeelink.light.color1,blt.3.1,192.168.1.20,,AA:BB:CC:DD:EE:FF,ffeeddccbbaa99887766554433221100\n\
+             Unbound sensor,yeelink.light.color1,blt.3.2,192.168.1.20,,AA:BB:CC:DD:EE:FF,\n"
+        );
+    }
+
+    #[test]
+    fn miio_cli() {
+        assert_eq!(
+            export_devices(&devices(), ExportFormat::MiioCli).unwrap(),
+            "did      model                 name            localip       token                             ble_key\n\
+             1        yeelink.light.color1  Desk \"lamp\"     192.168.1.20  00112233445566778899aabbccddeeff\n\
+             blt.3.1  yeelink.light.color1  Sensor, hall    192.168.1.20                                    ffeeddccbbaa99887766554433221100\n\
+             blt.3.2  yeelink.light.color1  Unbound sensor  192.168.1.20\n"
+        );
+    }
+
+    #[test]
+    fn parse_format() {
+        for format in ExportFormat::ALL {
+            assert_eq!(format.name().parse::<ExportFormat>().unwrap(), format);
+            assert_eq!(serde_json::to_value(format).unwrap(), json!(format.name()));
+        }
+        assert!("yaml".parse::<ExportFormat>().is_err());
+    }
+}
@@ -0,0 +1,116 @@
+//! Homes and rooms from `/v2/homeroom/gethome`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{cloud_error, lenient, MiCloudProtocol, Result, Transport};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Room {
+    #[serde(deserialize_with = "lenient::string")]
+    pub id: String,
+    pub name: String,
+    /// Id of the home the room belongs to
+    #[serde(alias = "parentid", default, deserialize_with = "lenient::string")]
+    pub parent_id: String,
+    /// Devices placed in this room
+    #[serde(default)]
+    pub dids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Home {
+    /// Matches `Device::family_id`
+    #[serde(deserialize_with = "lenient::string")]
+    pub id: String,
+    pub name: String,
+    /// User id of the owner, differs from the account for shared homes
+    #[serde(default)]
+    pub uid: u64,
+    /// Devices of the home that are not placed in a room
+    #[serde(default)]
+    pub dids: Vec<String>,
+    #[serde(alias = "roomlist", default)]
+    pub rooms: Vec<Room>,
+}
+
+impl Home {
+    /// The room `did` is placed in, `None` if it is not in any.
+    pub fn room_of(&self, did: &str) -> Option<&Room> {
+        self.rooms
+            .iter()
+            .find(|room| room.dids.iter().any(|d| d == did))
+    }
+
+    /// Every device of the home, room by room, then the unplaced ones.
+    pub fn all_dids(&self) -> impl Iterator<Item = &str> {
+        self.rooms
+            .iter()
+            .flat_map(|room| &room.dids)
+            .chain(&self.dids)
+            .map(String::as_str)
+    }
+}
+
+fn parse_homes(res: &Value) -> Result<Vec<Home>> {
+    match res["result"]["homelist"].as_array() {
+        Some(list) => Ok(serde_json::from_value(Value::Array(list.clone()))?),
+        None => Err(cloud_error(res, "Get homes failed")),
+    }
+}
+
+impl MiCloudProtocol {
+    /// Lists homes with their rooms, including homes shared with the account.
+    pub async fn get_homes(&self, country: Option<&str>) -> Result<Vec<Home>> {
+        let country = country.unwrap_or(self.country.as_str());
+        let req = json!({
+            "fg": true,
+            "fetch_share": true,
+            "fetch_share_dev": true,
+            "limit": 300,
+            "app_ver": 7,
+        });
+        let res = self
+            .request_with(Transport::Rc4, "/v2/homeroom/gethome", req, country)
+            .await?;
+        parse_homes(&res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_home_list() {
+        let res = json!({
+            "code": 0,
+            "message": "ok",
+            "result": {
+                "homelist": [{
+                    "id": "100000001",
+                    "name": "Flat",
+                    "uid": 1234567890,
+                    "dids": ["4"],
+                    "roomlist": [
+                        {"id": "200000001", "name": "Kitchen", "parentid": "100000001", "dids": ["1", "2"]},
+                        {"id": 200000002, "name": "Hall", "parentid": "100000001"}
+                    ],
+                    "bssid": "", "city_id": 0, "address": ""
+                }],
+                "has_more": false
+            }
+        });
+        let homes = parse_homes(&res).unwrap();
+        let home = &homes[0];
+        assert_eq!(home.id, "100000001");
+        assert_eq!(home.rooms[1].id, "200000002");
+        assert!(home.rooms[1].dids.is_empty());
+        assert_eq!(home.room_of("2").unwrap().name, "Kitchen");
+        assert_eq!(home.room_of("4"), None);
+        assert_eq!(home.all_dids().collect::<Vec<_>>(), ["1", "2", "4"]);
+
+        let err = parse_homes(&json!({ "code": 3, "message": "auth err" })).unwrap_err();
+        assert_eq!(err.to_string(), "auth err");
+    }
+}
@@ -0,0 +1,426 @@
+//! Local miIO protocol over UDP (port 54321).
+//!
+//! Every packet starts with a 32 byte header:
+//!
+//! | bytes  | field                                              |
+//! |--------|----------------------------------------------------|
+//! | 0..2   | magic `0x2131`                                     |
+//! | 2..4   | total packet length                                |
+//! | 4..8   | unknown, `0` (`0xFFFFFFFF` in hello)               |
+//! | 8..12  | device id                                          |
+//! | 12..16 | stamp, seconds since device start                  |
+//! | 16..32 | MD5 checksum (or token in hello reply)             |
+//!
+//! The payload is JSON-RPC encrypted with AES-128-CBC, where
+//! `key = md5(token)` and `iv = md5(key + token)`.
+
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use crypto_hash::{digest, Algorithm};
+use log::debug;
+use rand::{thread_rng, Rng};
+use serde_json::{json, Value};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, time::timeout};
+
+use crate::{Device, Error, Result};
+
+pub const MIIO_PORT: u16 = 54321;
+
+const MAGIC: u16 = 0x2131;
+const HEADER_LEN: usize = 32;
+const MAX_PACKET_LEN: usize = 4096;
+
+type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+fn md5(data: &[u8]) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&digest(Algorithm::MD5, data));
+    out
+}
+
+/// The 32 byte packet a device answers with its id and stamp.
+pub(crate) fn hello_packet() -> [u8; HEADER_LEN] {
+    let mut packet = [0xFFu8; HEADER_LEN];
+    packet[0..2].copy_from_slice(&MAGIC.to_be_bytes());
+    packet[2..4].copy_from_slice(&(HEADER_LEN as u16).to_be_bytes());
+    packet
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub length: u16,
+    pub unknown: u32,
+    pub device_id: u32,
+    pub stamp: u32,
+    pub checksum: [u8; 16],
+}
+
+impl Header {
+    pub fn parse(packet: &[u8]) -> Result<Self> {
+        if packet.len() < HEADER_LEN {
+            return Err(Error::Decode(format!(
+                "miIO packet too short: {} bytes",
+                packet.len()
+            )));
+        }
+        let magic = u16::from_be_bytes([packet[0], packet[1]]);
+        if magic != MAGIC {
+            return Err(Error::Decode(format!(
+                "miIO packet has invalid magic {:#06x}",
+                magic
+            )));
+        }
+        let mut checksum = [0u8; 16];
+        checksum.copy_from_slice(&packet[16..32]);
+        Ok(Header {
+            length: u16::from_be_bytes([packet[2], packet[3]]),
+            unknown: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            device_id: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+            stamp: u32::from_be_bytes([packet[12], packet[13], packet[14], packet[15]]),
+            checksum,
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0..2].copy_from_slice(&MAGIC.to_be_bytes());
+        bytes[2..4].copy_from_slice(&self.length.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.unknown.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.device_id.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.stamp.to_be_bytes());
+        bytes[16..32].copy_from_slice(&self.checksum);
+        bytes
+    }
+}
+
+/// Parses the 32 hex chars token from the cloud device list.
+pub(crate) fn parse_token(token: &str) -> Result<[u8; 16]> {
+    let mut bytes = [0u8; 16];
+    hex::decode_to_slice(token.trim(), &mut bytes)
+        .map_err(|e| Error::Decode(format!("Invalid device token '{}': {}", token, e)))?;
+    Ok(bytes)
+}
+
+/// Packet encoder/decoder bound to a single device token.
+pub(crate) struct Cipher {
+    token: [u8; 16],
+    key: [u8; 16],
+    iv: [u8; 16],
+}
+
+impl Cipher {
+    pub fn new(token: [u8; 16]) -> Self {
+        let key = md5(&token);
+        let iv = md5(&[key, token].concat());
+        Cipher { token, key, iv }
+    }
+
+    fn checksum(&self, header: &[u8], data: &[u8]) -> [u8; 16] {
+        md5(&[&header[..16], &self.token, data].concat())
+    }
+
+    pub fn encode(&self, device_id: u32, stamp: u32, payload: &[u8]) -> Vec<u8> {
+        let data = Aes128CbcEnc::new(&self.key.into(), &self.iv.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(payload);
+        let mut header = Header {
+            length: (HEADER_LEN + data.len()) as u16,
+            unknown: 0,
+            device_id,
+            stamp,
+            checksum: [0u8; 16],
+        }
+        .to_bytes();
+        let checksum = self.checksum(&header, &data);
+        header[16..].copy_from_slice(&checksum);
+        [header.as_slice(), &data].concat()
+    }
+
+    pub fn decode(&self, packet: &[u8]) -> Result<(Header, Vec<u8>)> {
+        let header = Header::parse(packet)?;
+        let length = header.length as usize;
+        if length < HEADER_LEN || length > packet.len() {
+            return Err(Error::Decode(format!(
+                "miIO packet length {} does not match",
+                length
+            )));
+        }
+        let data = &packet[HEADER_LEN..length];
+        if self.checksum(packet, data) != header.checksum {
+            return Err(Error::decode(
+                "miIO packet checksum mismatch, is the token correct?",
+            ));
+        }
+        if data.is_empty() {
+            return Ok((header, vec![]));
+        }
+        let payload = Aes128CbcDec::new(&self.key.into(), &self.iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(data)
+            .map_err(|e| Error::Decode(format!("Failed to decrypt miIO payload: {}", e)))?;
+        Ok((header, payload))
+    }
+}
+
+/// JSON-RPC client for a single device reachable in the local network.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut device = miio::LanDevice::connect(
+///     "192.168.1.10".parse()?,
+///     "ffffffffffffffffffffffffffffffff",
+/// )
+/// .await?;
+/// let props = device.call("get_prop", Some(serde_json::json!(["power"]))).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct LanDevice {
+    addr: SocketAddr,
+    socket: UdpSocket,
+    cipher: Cipher,
+    device_id: u32,
+    stamp: u32,
+    stamp_received_at: Instant,
+    next_id: u64,
+    timeout: Duration,
+}
+
+impl LanDevice {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Connects to a device on the default miIO port and performs the hello handshake.
+    pub async fn connect(ip: IpAddr, token: &str) -> Result<Self> {
+        Self::connect_addr(SocketAddr::new(ip, MIIO_PORT), token).await
+    }
+
+    pub async fn connect_addr(addr: SocketAddr, token: &str) -> Result<Self> {
+        let bind_addr: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        let mut device = LanDevice {
+            addr,
+            socket,
+            cipher: Cipher::new(parse_token(token)?),
+            device_id: 0,
+            stamp: 0,
+            stamp_received_at: Instant::now(),
+            next_id: thread_rng().gen_range(1..10_000),
+            timeout: Self::DEFAULT_TIMEOUT,
+        };
+        device.handshake().await?;
+        Ok(device)
+    }
+
+    /// Connects using the `localip` and `token` reported by the cloud.
+    pub async fn from_device(device: &Device) -> Result<Self> {
+        let ip = device
+            .localip
+            .parse::<IpAddr>()
+            .map_err(|_| Error::Decode(format!("Device {} has no valid local ip", device.did)))?;
+        Self::connect(ip, &device.token).await
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sends hello and stores device id and stamp required to build request packets.
+    pub async fn handshake(&mut self) -> Result<()> {
+        self.socket.send_to(&hello_packet(), self.addr).await?;
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let header = timeout(self.timeout, async {
+            loop {
+                let (len, from) = self.socket.recv_from(&mut buf).await?;
+                if from != self.addr {
+                    continue;
+                }
+                match Header::parse(&buf[..len]) {
+                    Ok(header) => return Ok::<_, Error>(header),
+                    Err(e) => debug!("[miio::lan] ignoring invalid hello reply: {}", e),
+                }
+            }
+        })
+        .await
+        .map_err(|_| Error::Timeout(format!("miIO handshake with {} timed out", self.addr)))??;
+
+        self.device_id = header.device_id;
+        self.stamp = header.stamp;
+        self.stamp_received_at = Instant::now();
+        debug!(
+            "[miio::lan] handshake: addr={}, device_id={}, stamp={}",
+            self.addr, self.device_id, self.stamp
+        );
+        Ok(())
+    }
+
+    /// Sends a JSON-RPC request and waits for the reply with the matching id.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` on timeout, on a packet that fails checksum/decryption,
+    /// or if the device answers with an `error` object.
+    pub async fn call(&mut self, method: &str, params: Option<Value>) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = json!({
+            "id": id,
+            "method": method,
+            "params": params.unwrap_or_else(|| json!([])),
+        });
+
+        let stamp = self.stamp + self.stamp_received_at.elapsed().as_secs() as u32 + 1;
+        let packet = self
+            .cipher
+            .encode(self.device_id, stamp, request.to_string().as_bytes());
+        self.socket.send_to(&packet, self.addr).await?;
+
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let response = timeout(self.timeout, async {
+            loop {
+                let (len, from) = self.socket.recv_from(&mut buf).await?;
+                if from != self.addr {
+                    continue;
+                }
+                let (_, payload) = match self.cipher.decode(&buf[..len]) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        debug!("[miio::lan] ignoring packet: {}", e);
+                        continue;
+                    }
+                };
+                let response = parse_payload(&payload)?;
+                if response["id"].as_u64() == Some(id) {
+                    return Ok::<_, Error>(response);
+                }
+                debug!("[miio::lan] ignoring reply for id {}", response["id"]);
+            }
+        })
+        .await
+        .map_err(|_| {
+            Error::Timeout(format!("miIO call '{}' to {} timed out", method, self.addr))
+        })??;
+
+        if let Some(error) = response.get("error") {
+            return Err(Error::DeviceError {
+                code: error["code"].as_i64().unwrap_or(-1),
+                message: format!(
+                    "miIO call '{}' failed: {}",
+                    method,
+                    error["message"].as_str().unwrap_or(&error.to_string())
+                ),
+            });
+        }
+        Ok(response["result"].clone())
+    }
+}
+
+fn parse_payload(payload: &[u8]) -> Result<Value> {
+    // Some firmwares terminate the JSON with a null byte
+    let end = payload
+        .iter()
+        .rposition(|b| *b != 0)
+        .map_or(0, |pos| pos + 1);
+    Ok(serde_json::from_slice(&payload[..end])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "00112233445566778899aabbccddeeff";
+
+    #[test]
+    fn cipher_key_and_iv() {
+        let cipher = Cipher::new(parse_token(TOKEN).unwrap());
+        assert_eq!(hex::encode(cipher.key), "6e8311168ee16d6aa1aa48c64145003c");
+        assert_eq!(hex::encode(cipher.iv), "6f434fa9acd75da73e5fb999f641cda2");
+    }
+
+    #[test]
+    fn encode_decode() {
+        let cipher = Cipher::new(parse_token(TOKEN).unwrap());
+        let packet = cipher.encode(0x1234, 42, br#"{"id":1,"method":"miIO.info"}"#);
+        assert_eq!(packet.len() % 16, 0);
+
+        let (header, payload) = cipher.decode(&packet).unwrap();
+        assert_eq!(header.device_id, 0x1234);
+        assert_eq!(header.stamp, 42);
+        assert_eq!(header.length as usize, packet.len());
+        assert_eq!(payload, br#"{"id":1,"method":"miIO.info"}"#);
+
+        let mut tampered = packet.clone();
+        tampered[40] ^= 1;
+        assert!(cipher.decode(&tampered).is_err());
+    }
+
+    #[test]
+    fn hello() {
+        let hello = hello_packet();
+        assert_eq!(&hello[..4], &[0x21, 0x31, 0x00, 0x20]);
+        assert!(hello[4..].iter().all(|b| *b == 0xFF));
+    }
+
+    /// Emulates a device that answers hello and a single request,
+    /// sending a stale reply first to check id correlation.
+    async fn fake_device(socket: UdpSocket) {
+        let cipher = Cipher::new(parse_token(TOKEN).unwrap());
+        let mut buf = [0u8; MAX_PACKET_LEN];
+
+        let (_, client) = socket.recv_from(&mut buf).await.unwrap();
+        let hello = Header {
+            length: HEADER_LEN as u16,
+            unknown: 0,
+            device_id: 0xabcd,
+            stamp: 1000,
+            checksum: [0xFF; 16],
+        };
+        socket.send_to(&hello.to_bytes(), client).await.unwrap();
+
+        let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+        let (header, payload) = cipher.decode(&buf[..len]).unwrap();
+        assert_eq!(header.device_id, 0xabcd);
+        assert!(header.stamp > 1000);
+        let request = parse_payload(&payload).unwrap();
+        assert_eq!(request["method"], "get_prop");
+        let id = request["id"].as_u64().unwrap();
+
+        let stale = json!({ "id": id - 1, "result": ["off"] });
+        let reply = json!({ "id": id, "result": ["on"] });
+        for message in [stale, reply] {
+            let mut payload = message.to_string().into_bytes();
+            payload.push(0);
+            let packet = cipher.encode(0xabcd, 1001, &payload);
+            socket.send_to(&packet, client).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn call_fake_device() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = tokio::spawn(fake_device(socket));
+
+        let mut device = LanDevice::connect_addr(addr, TOKEN).await.unwrap();
+        assert_eq!(device.device_id(), 0xabcd);
+        let result = device
+            .call("get_prop", Some(json!(["power"])))
+            .await
+            .unwrap();
+        assert_eq!(result, json!(["on"]));
+        server.await.unwrap();
+    }
+}
@@ -1,4 +1,3 @@
-extern crate anyhow;
 extern crate base64;
 extern crate crypto_hash;
 extern crate hex;
@@ -11,22 +10,23 @@ extern crate sha2;
 extern crate urlencoding;
 
 use ::hmac::{Hmac, Mac};
-use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use crypto_hash::{hex_digest, Algorithm};
 use hmac::NewMac;
 use log::debug;
 use rand::{thread_rng, Rng};
+use rc4::{consts::U32, KeyInit, Rc4, StreamCipher};
 use regex::Regex;
 use reqwest::{
     cookie::{CookieStore, Jar},
     header, Client, Url,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Number, Value};
+use serde_json::{json, Map, Value};
 use sha2::{Digest, Sha256};
 use std::{
     iter,
-    sync::Arc,
+    sync::{Arc, PoisonError, RwLock},
     time::{SystemTime, UNIX_EPOCH},
     vec,
 };
@@ -35,6 +35,76 @@ use uuid::Uuid;
 mod async_challenge;
 use crate::async_challenge::{AsyncChallengeState, ChallengeSolution};
 
+mod error;
+pub use crate::error::{Error, Result};
+
+mod lenient;
+
+mod urls;
+pub use crate::urls::UrlsConfig;
+
+mod lan;
+pub use crate::lan::{LanDevice, MIIO_PORT};
+
+mod miot;
+pub use crate::miot::{
+    MiotActionRequest, MiotActionResult, MiotPropertyRequest, MiotPropertyResult,
+    MiotSetPropertyRequest,
+};
+
+mod homes;
+pub use crate::homes::{Home, Room};
+
+mod devices;
+pub use crate::devices::{DeviceListOptions, Ownership};
+
+mod ble;
+
+mod tree;
+pub use crate::tree::{DeviceNode, DeviceTree};
+
+mod scenes;
+pub use crate::scenes::{Scene, SceneAction, SceneKind, SceneSource, SceneTrigger};
+
+mod batch;
+pub use crate::batch::{
+    BatchOutcome, BatchReport, BatchResult, DeviceSelector, DEFAULT_BATCH_CONCURRENCY,
+};
+
+mod yeelight;
+pub use crate::yeelight::{
+    discover_bulbs, discover_bulbs_on, match_bulbs, supports_lan_control, BulbMatch, BulbProps,
+    DiscoveredBulb, Effect, FlowAction, FlowStep, YeelightBulb, YEELIGHT_PORT,
+};
+
+mod poller;
+pub use crate::poller::{PollEvent, Poller, PropertyChange, PropertyId};
+
+mod spec;
+pub use crate::spec::{
+    MiotAction, MiotEvent, MiotProperty, MiotService, MiotSpec, MiotSpecClient, MiotValueListItem,
+};
+
+mod discovery;
+pub use crate::discovery::{
+    discover, discover_on, match_devices, DeviceReachability, DiscoveredDevice, DiscoveryReport,
+};
+
+mod export;
+pub use crate::export::{export_devices, ExportFormat};
+
+mod recorder;
+use crate::recorder::ApiCall;
+pub use crate::recorder::{Exchange, Replay, TrafficRecorder};
+
+#[cfg(test)]
+mod test_util;
+
+mod accounts;
+pub use crate::accounts::{
+    AccountDevice, AccountError, AccountInfo, AccountRegistry, MergedDevices,
+};
+
 /// Response codes from Xiaomi login API
 ///
 /// Note: These are educated guesses based on behavior, not official documentation
@@ -97,13 +167,37 @@ enum Handle2FaResult {
 }
 
 fn parse_response_json(str: &str) -> serde_json::Result<Value> {
-    let str = if str.starts_with("&&&START&&&") {
-        &str[11..]
+    let str = str.strip_prefix("&&&START&&&").unwrap_or(str);
+
+    serde_json::from_str(str)
+}
+
+/// Builds an error from a cloud response without `result`. The cloud reports
+/// failures either as an `error` object or as top level `code`/`message`.
+fn cloud_error(res: &Value, fallback_msg: &str) -> Error {
+    let error = if res["error"].is_object() {
+        &res["error"]
     } else {
-        str
+        res
     };
+    Error::CloudError {
+        code: error["code"].as_i64().unwrap_or(-1),
+        message: error["message"]
+            .as_str()
+            .filter(|m| !m.is_empty())
+            .unwrap_or(fallback_msg)
+            .to_string(),
+    }
+}
 
-    serde_json::from_str(str)
+/// The cloud answers an expired `serviceToken` with HTTP 401, some endpoints
+/// with a 200 and an `auth err` message instead.
+fn is_auth_expired(res: &Result<Value>) -> bool {
+    match res {
+        Err(Error::Http(401)) => true,
+        Ok(res) => res["message"].as_str() == Some("auth err"),
+        Err(_) => false,
+    }
 }
 
 fn serde_value_to_string(value: &Value) -> String {
@@ -129,40 +223,59 @@ fn object_to_query_string(obj: &Value) -> String {
     obj_as_query_string.replacen('&', "", 1)
 }
 
-#[derive(serde::Serialize)]
-pub struct UrlsConfig {
-    cn: String,
-    de: String,
-    ru: String,
-    sg: String,
-    tw: String,
-    us: String,
-    i2: String,
-    login_step1: String,
-    login_step2: String,
+/// Tokens issued by a successful login.
+#[derive(Clone)]
+struct Credentials {
+    ssecurity: String,
+    user_id: String,
+    service_token: String,
 }
 
-impl UrlsConfig {
-    fn to_json(&self) -> Value {
-        json!(self)
+/// Credentials that `request` can replace through `&self` after a re-login.
+/// Shared by clones, so a re-login on one is seen by all of them.
+#[derive(Clone, Default)]
+struct CredentialsCell(Arc<RwLock<Option<Credentials>>>);
+
+impl CredentialsCell {
+    fn get(&self) -> Option<Credentials> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set(&self, credentials: Option<Credentials>) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = credentials;
     }
 }
 
+/// Mi Cloud client.
+///
+/// Clones share the captcha and 2FA challenge state, so a clone can run `login`
+/// while `captcha_solve`/`two_factor_solve` are called on the original. They
+/// also share the credentials until `logout` is called on one of them.
+///
+/// After a password login, requests rejected with an expired `serviceToken`
+/// log in again with the stored password hash and are replayed once.
+#[derive(Clone)]
 pub struct MiCloudProtocol {
     urls: UrlsConfig,
     username: Option<String>,
     password_md5: Option<String>,
-    ssecurity: Option<String>,
-    user_id: Option<String>,
+    credentials: CredentialsCell,
     country: String,
-    service_token: Option<String>,
     user_agent: String,
     client_id: String,
     locale: &'static str,
-    captcha_handler: Option<Box<dyn Fn(String) + Send + Sync>>,
+    captcha_handler: Option<Arc<dyn Fn(String) + Send + Sync>>,
     captcha_state: AsyncChallengeState<String>,
-    two_factor_handler: Option<Box<dyn Fn(String, String) + Send + Sync>>,
+    two_factor_handler: Option<Arc<dyn Fn(String, String) + Send + Sync>>,
     two_factor_state: AsyncChallengeState<String>,
+    session_handler: Option<Arc<dyn Fn(MiCloudSession) + Send + Sync>>,
+    /// Shared like the challenge state, so only one re-login prompts at a time
+    relogin_lock: Arc<tokio::sync::Mutex<()>>,
+    recorder: Option<Arc<TrafficRecorder>>,
+    replay: Option<Arc<Replay>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -170,50 +283,218 @@ struct MiCloudOkResponse<T = Value> {
     result: T,
 }
 
-#[derive(Serialize, Deserialize)]
-struct MiCloudErrorMessageResponse {
-    message: Option<String>,
-}
-
-#[derive(Serialize, Deserialize)]
-struct MiCloudErrorResponse {
-    error: MiCloudErrorMessageResponse,
-}
-
 #[derive(Serialize, Deserialize)]
 struct DeviceListResponse {
     list: Vec<Device>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A device as listed by the cloud.
+///
+/// Every field is optional and tolerates the wrong JSON type, so new or
+/// changed cloud fields never fail a listing. Keys the struct does not know
+/// are kept in `other_fields` and serialized back as they came.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct Device {
-    adminFlag: Number,
+    #[serde(rename = "adminFlag", deserialize_with = "lenient::int")]
+    admin_flag: i64,
+    #[serde(deserialize_with = "lenient::string")]
     bssid: String,
+    #[serde(deserialize_with = "lenient::string")]
     desc: String,
+    #[serde(deserialize_with = "lenient::string")]
     did: String,
-    extra: Value,
-    family_id: Number,
-    isOnline: bool,
+    #[serde(deserialize_with = "lenient::or_default")]
+    extra: DeviceExtra,
+    #[serde(deserialize_with = "lenient::uint")]
+    family_id: u64,
+    #[serde(rename = "isOnline", deserialize_with = "lenient::flag")]
+    is_online: bool,
+    #[serde(deserialize_with = "lenient::string")]
     latitude: String,
+    #[serde(deserialize_with = "lenient::string")]
     localip: String,
+    #[serde(deserialize_with = "lenient::string")]
     longitude: String,
+    #[serde(deserialize_with = "lenient::string")]
     mac: String,
+    #[serde(deserialize_with = "lenient::string")]
     model: String,
+    #[serde(deserialize_with = "lenient::string")]
     name: String,
+    #[serde(deserialize_with = "lenient::string")]
     p2p_id: String,
+    #[serde(deserialize_with = "lenient::string")]
     parent_id: String,
+    #[serde(deserialize_with = "lenient::string")]
     parent_model: String,
+    #[serde(deserialize_with = "lenient::string")]
     password: String,
-    pd_id: Number,
-    permitLevel: Number,
+    #[serde(deserialize_with = "lenient::int")]
+    pd_id: i64,
+    #[serde(rename = "permitLevel", deserialize_with = "lenient::int")]
+    permit_level: i64,
+    #[serde(deserialize_with = "lenient::string")]
     pid: String,
-    reset_flag: Number,
-    rssi: Number,
-    shareFlag: Number,
-    show_mode: Number,
+    #[serde(deserialize_with = "lenient::int")]
+    reset_flag: i64,
+    #[serde(deserialize_with = "lenient::int")]
+    rssi: i64,
+    #[serde(rename = "shareFlag", deserialize_with = "lenient::int")]
+    share_flag: i64,
+    #[serde(deserialize_with = "lenient::int")]
+    show_mode: i64,
+    #[serde(deserialize_with = "lenient::string")]
     ssid: String,
+    #[serde(deserialize_with = "lenient::string")]
     token: String,
-    uid: Number,
+    #[serde(deserialize_with = "lenient::uint")]
+    uid: u64,
+    /// Derived from `shareFlag`/`permitLevel` when listed from the cloud
+    #[serde(deserialize_with = "lenient::or_default")]
+    ownership: Ownership,
+    /// Beacon key of BLE devices, see `MiCloudProtocol::fill_ble_beacon_keys`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ble_key: Option<String>,
+    #[serde(flatten)]
+    other_fields: Map<String, Value>,
+}
+
+/// Parsed `extra` object of a device.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct DeviceExtra {
+    #[serde(rename = "isSetPincode", deserialize_with = "lenient::int")]
+    pub is_set_pincode: i64,
+    #[serde(deserialize_with = "lenient::string")]
+    pub fw_version: String,
+    #[serde(rename = "needVerifyCode", deserialize_with = "lenient::int")]
+    pub need_verify_code: i64,
+    #[serde(deserialize_with = "lenient::string")]
+    pub mcu_version: String,
+    /// Keys not listed above, e.g. `pincodeType` or `isPasswordEncrypt`
+    #[serde(flatten)]
+    pub other_fields: Map<String, Value>,
+}
+
+impl Device {
+    pub fn did(&self) -> &str {
+        &self.did
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// miIO token, empty for devices that have none, e.g. BLE or sub-devices
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// IP address in the device's network, empty if unknown
+    pub fn local_ip(&self) -> &str {
+        &self.localip
+    }
+
+    pub fn mac(&self) -> &str {
+        &self.mac
+    }
+
+    pub fn ssid(&self) -> &str {
+        &self.ssid
+    }
+
+    pub fn bssid(&self) -> &str {
+        &self.bssid
+    }
+
+    pub fn rssi(&self) -> i64 {
+        self.rssi
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.is_online
+    }
+
+    /// Gateway of a sub-device, empty otherwise
+    pub fn parent_id(&self) -> &str {
+        &self.parent_id
+    }
+
+    pub fn parent_model(&self) -> &str {
+        &self.parent_model
+    }
+
+    /// Matches `Home::id`
+    pub fn family_id(&self) -> u64 {
+        self.family_id
+    }
+
+    /// User id of the owner
+    pub fn uid(&self) -> u64 {
+        self.uid
+    }
+
+    pub fn permit_level(&self) -> i64 {
+        self.permit_level
+    }
+
+    pub fn extra(&self) -> &DeviceExtra {
+        &self.extra
+    }
+
+    /// Fields the cloud sent that `Device` has no accessor for.
+    pub fn other_fields(&self) -> &Map<String, Value> {
+        &self.other_fields
+    }
+
+    pub fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    /// Bluetooth devices have a `blt.` did and no miIO token.
+    pub fn is_ble(&self) -> bool {
+        self.did.starts_with("blt.")
+    }
+
+    pub fn ble_key(&self) -> Option<&str> {
+        self.ble_key.as_deref()
+    }
+
+    pub(crate) fn update_ownership(&mut self) {
+        self.ownership = Ownership::from_flags(self.share_flag, self.permit_level);
+    }
+}
+
+/// Authenticated Mi Cloud session that can be persisted between app runs.
+///
+/// Obtained from a logged-in `MiCloudProtocol` via `get_session` and turned
+/// back into a ready-to-use instance with `MiCloudProtocol::from_session`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MiCloudSession {
+    pub user_id: String,
+    pub ssecurity: String,
+    pub service_token: String,
+    pub client_id: String,
+    pub country: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+}
+
+/// Wire format of a Mi Cloud API request.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    /// Plain form body signed with HMAC-SHA256
+    #[default]
+    Signed,
+    /// Params and response encrypted with RC4 keyed by the signed nonce,
+    /// required by many newer endpoints
+    Rc4,
 }
 
 impl MiCloudProtocol {
@@ -228,25 +509,12 @@ impl MiCloudProtocol {
         // Another known type of client_id (deviceId) is "wb_{uuidv4}", which likely stands for "web browser"
         let client_id: String = format!("android_{uuidv4}").to_string();
 
-        let xiaomi_base_url = "api.io.mi.com/app";
         MiCloudProtocol {
-            urls: UrlsConfig {
-                cn: format!("https://{}", xiaomi_base_url),
-                de: format!("https://de.{}", xiaomi_base_url),
-                ru: format!("https://ru.{}", xiaomi_base_url),
-                sg: format!("https://sg.{}", xiaomi_base_url),
-                tw: format!("https://tw.{}", xiaomi_base_url),
-                us: format!("https://us.{}", xiaomi_base_url),
-                i2: format!("https://i2.{}", xiaomi_base_url),
-                login_step1: "https://account.xiaomi.com/pass/serviceLogin".to_string(),
-                login_step2: "https://account.xiaomi.com/pass/serviceLoginAuth2".to_string(),
-            },
+            urls: UrlsConfig::default(),
             username: None,
             password_md5: None,
-            ssecurity: None,
+            credentials: CredentialsCell::default(),
             country: "cn".to_string(),
-            user_id: None,
-            service_token: None,
             user_agent: format!(
                 "Android-7.1.1-1.0.0-ONEPLUS A3010-136-{} APP/xiaomi.smarthome APPV/62830",
                 agent_id.clone()
@@ -257,9 +525,67 @@ impl MiCloudProtocol {
             captcha_state: AsyncChallengeState::<String>::new(),
             two_factor_handler: None,
             two_factor_state: AsyncChallengeState::<String>::new(),
+            session_handler: None,
+            relogin_lock: Arc::new(tokio::sync::Mutex::new(())),
+            recorder: None,
+            replay: None,
         }
     }
 
+    /// Creates an instance that is already logged in with a previously saved session,
+    /// so `request`, `get_devices` and `call_device` work without calling `login`.
+    ///
+    /// The password hash is never part of a session, so once the saved
+    /// serviceToken is rejected requests fail with `Error::SessionExpired`
+    /// instead of logging in again; call `login` to recover.
+    pub fn from_session(session: MiCloudSession) -> Self {
+        let mut protocol = Self::new();
+        protocol.set_session(session);
+        protocol
+    }
+
+    /// Replaces the current session, keeping handlers and challenge state.
+    pub fn set_session(&mut self, session: MiCloudSession) {
+        self.set_country(&session.country);
+        self.client_id = session.client_id;
+        self.username = session.username;
+        self.password_md5 = None;
+        self.credentials.set(Some(Credentials {
+            ssecurity: session.ssecurity,
+            user_id: session.user_id,
+            service_token: session.service_token,
+        }));
+    }
+
+    /// Returns the current session, or `None` if not logged in.
+    pub fn get_session(&self) -> Option<MiCloudSession> {
+        let credentials = self.credentials.get()?;
+        Some(MiCloudSession {
+            user_id: credentials.user_id,
+            ssecurity: credentials.ssecurity,
+            service_token: credentials.service_token,
+            client_id: self.client_id.clone(),
+            country: self.country.clone(),
+            username: self.username.clone(),
+        })
+    }
+
+    pub(crate) fn user_id(&self) -> Option<String> {
+        self.credentials.get().map(|c| c.user_id)
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        self.credentials.get().is_some()
+    }
+
+    /// Forgets the current session and credentials.
+    pub fn logout(&mut self) {
+        self.username = None;
+        self.password_md5 = None;
+        // A fresh cell, so other clones keep their session
+        self.credentials = CredentialsCell::default();
+    }
+
     pub fn get_available_countries(&self) -> Vec<Vec<&'static str>> {
         vec![
             vec!["cn", "China"],
@@ -281,10 +607,10 @@ impl MiCloudProtocol {
     ///
     /// # Examples
     ///
-    /// ```
-    /// use mi_cloud_protocol::MiCloudProtocol;
+    /// ```no_run
+    /// use miio::MiCloudProtocol;
     ///
-    /// #[tokio::main]
+    /// #[tokio::main(flavor = "current_thread")]
     /// async fn main() {
     ///     let mut mi_cloud = MiCloudProtocol::new();
     ///     mi_cloud.login("my_username", "my_password").await.unwrap();
@@ -295,8 +621,18 @@ impl MiCloudProtocol {
     ///
     /// Returns `Err` if authentication fails.
     pub async fn login(&mut self, username: &str, password: &str) -> Result<()> {
+        let password_md5 = hex_digest(Algorithm::MD5, password.as_bytes()).to_uppercase();
+        let credentials = self.authenticate(username, &password_md5).await?;
+        self.username = Some(username.to_string());
+        self.password_md5 = Some(password_md5);
+        self.credentials.set(Some(credentials));
+        Ok(())
+    }
+
+    /// Runs the login flow with an uppercase MD5 password hash.
+    async fn authenticate(&self, username: &str, password_md5: &str) -> Result<Credentials> {
         let jar = Arc::new(Jar::default());
-        let url = "https://account.xiaomi.com".parse::<Url>().unwrap();
+        let url = self.urls.account.parse::<Url>().map_err(Error::decode)?;
         jar.add_cookie_str(
             &format!("userId={}; deviceId={}", username, self.client_id),
             &url,
@@ -304,18 +640,17 @@ impl MiCloudProtocol {
         let client = reqwest::Client::builder()
             .cookie_provider(Arc::clone(&jar))
             .build()?;
-        let password_md5 = hex_digest(Algorithm::MD5, password.as_bytes()).to_uppercase();
 
         // Step 1: Get _sign
         let step1_data = self.login_step1(&client).await?;
         let sign = match step1_data["_sign"].as_str() {
             Some(s) => s.to_string(),
-            None => return Err(anyhow!("Login step 1 failed: No '_sign' in response")),
+            None => return Err(Error::login("Login step 1 failed: No '_sign' in response")),
         };
 
         // Step 2: This handles captcha internally via looping with the _sign from the response
         let login_step2_res = self
-            .login_step2(&client, username, password_md5.as_str(), &sign)
+            .login_step2(&client, username, password_md5, &sign)
             .await?;
 
         match login_step2_res {
@@ -325,12 +660,11 @@ impl MiCloudProtocol {
                 location,
             } => {
                 let token = self.login_step3(&client, location).await?;
-                self.username = Some(username.to_string());
-                self.password_md5 = Some(password_md5);
-                self.ssecurity = Some(ssecurity);
-                self.user_id = Some(user_id.to_string());
-                self.service_token = Some(token);
-                return Ok(());
+                Ok(Credentials {
+                    ssecurity,
+                    user_id: user_id.to_string(),
+                    service_token: token,
+                })
             }
             LoginStep2Result::TwoFactorRequired { notification_url } => {
                 debug!(
@@ -345,14 +679,11 @@ impl MiCloudProtocol {
                         ssecurity,
                         user_id,
                         service_token,
-                    } => {
-                        self.username = Some(username.to_string());
-                        self.password_md5 = Some(password_md5);
-                        self.ssecurity = Some(ssecurity);
-                        self.user_id = Some(user_id.to_string());
-                        self.service_token = Some(service_token);
-                        return Ok(());
-                    }
+                    } => Ok(Credentials {
+                        ssecurity,
+                        user_id: user_id.to_string(),
+                        service_token,
+                    }),
                 }
             }
         }
@@ -382,36 +713,25 @@ impl MiCloudProtocol {
         };
 
         let country = country.unwrap_or(self.country.as_str());
-        let res = self
-            .request("/home/device_list", req, country)
-            .await
-            .map_err(|e| anyhow!(e))?;
+        let res = self.request("/home/device_list", req, country).await?;
 
         if !res["result"].is_null() {
             let parsed_res: MiCloudOkResponse<DeviceListResponse> =
                 serde_json::from_value(res.clone())?;
-            let devices = parsed_res.result.list;
+            let mut devices = parsed_res.result.list;
+            devices.iter_mut().for_each(Device::update_ownership);
             Ok(devices)
         } else {
-            let parsed_err: MiCloudErrorResponse = serde_json::from_value(res.clone())?;
-            let message = parsed_err
-                .error
-                .message
-                .unwrap_or("Get devices failed".to_string());
-            Err(anyhow!(message))
+            Err(cloud_error(&res, "Get devices failed"))
         }
     }
 
-    pub async fn get_device<'a>(
-        &self,
-        device_id: &str,
-        country: Option<&str>,
-    ) -> Result<Vec<Device>> {
+    pub async fn get_device(&self, device_id: &str, country: Option<&str>) -> Result<Vec<Device>> {
         let device_ids = Some(vec![device_id]);
         self.get_devices(device_ids.as_deref(), country).await
     }
 
-    pub async fn call_device<'a>(
+    pub async fn call_device(
         &self,
         device_id: &str,
         method: &str,
@@ -424,39 +744,56 @@ impl MiCloudProtocol {
         let fallback_msg = format!("Miio call for device {} failed", device_id);
         let res = self
             .request(&format!(r"/home/rpc/{}", device_id), req, country)
-            .await
-            .with_context(|| fallback_msg.to_string())?;
+            .await?;
 
         if !res["result"].is_null() {
             Ok(res["result"].clone())
         } else {
-            let parsed_err: MiCloudErrorResponse = serde_json::from_value(res.clone())?;
-            let message = parsed_err.error.message.unwrap_or(fallback_msg.to_string());
-            Err(anyhow!(message))
+            Err(cloud_error(&res, &fallback_msg))
         }
     }
 
+    /// Calls any API path, e.g. `/v2/homeroom/gethome`, and returns the raw
+    /// response. `request` re-login and replay rules apply.
+    pub async fn call_api(
+        &self,
+        path: &str,
+        data: Value,
+        transport: Transport,
+        country: Option<&str>,
+    ) -> Result<Value> {
+        let country = country.unwrap_or(self.country.as_str());
+        self.request_with(transport, path, data, country).await
+    }
+
     pub fn _override_urls(&mut self, urls: UrlsConfig) {
         self.urls = urls;
     }
 
     pub fn _set_captcha_handler(&mut self, handler: Box<dyn Fn(String) + Send + Sync>) {
-        self.captcha_handler = Some(handler);
+        self.captcha_handler = Some(Arc::from(handler));
+    }
+
+    /// Called with the new session after an automatic re-login, e.g. to persist it.
+    pub fn _set_session_handler(&mut self, handler: Box<dyn Fn(MiCloudSession) + Send + Sync>) {
+        self.session_handler = Some(Arc::from(handler));
     }
 
     pub fn _set_two_factor_handler(&mut self, handler: Box<dyn Fn(String, String) + Send + Sync>) {
-        self.two_factor_handler = Some(handler);
+        self.two_factor_handler = Some(Arc::from(handler));
     }
 
     async fn fetch_captcha_b64_data_url(&self, client: &Client, path: &str) -> Result<String> {
-        let resp = client
-            .get(format!("https://account.xiaomi.com{path}"))
-            .header(header::USER_AGENT, &self.user_agent)
-            .send()
+        let resp = self
+            .send(
+                client
+                    .get(self.urls.account_url(path))
+                    .header(header::USER_AGENT, &self.user_agent),
+            )
             .await?;
         let status = resp.status();
         if !status.is_success() {
-            return Err(anyhow!("Failed to fetch captcha image: HTTP {}", status));
+            return Err(Error::Http(status.as_u16()));
         }
         let mime = resp
             .headers()
@@ -465,10 +802,7 @@ impl MiCloudProtocol {
             .unwrap_or("image/png")
             .to_string();
         let body = resp.bytes().await?;
-        Ok(format!(
-            "data:{mime};base64,{}",
-            base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &body)
-        ))
+        Ok(format!("data:{mime};base64,{}", STANDARD.encode(&body)))
     }
 
     const MAX_STEP1_CAPTCHA_FAILURES: u8 = 3;
@@ -486,25 +820,27 @@ impl MiCloudProtocol {
                 query.push(("captCode", c.clone()));
             }
 
-            let resp = client
-                .get(self.urls.login_step1.clone())
-                .header(header::USER_AGENT, &self.user_agent)
-                .query(&query)
-                .send()
+            let resp = self
+                .send(
+                    client
+                        .get(self.urls.login_step1_url())
+                        .header(header::USER_AGENT, &self.user_agent)
+                        .query(&query),
+                )
                 .await?;
             let status = resp.status();
             let text = resp.text().await?;
             if !status.is_success() {
-                return Err(anyhow!("Login step 1 failed: HTTP {}", status));
+                return Err(Error::Http(status.as_u16()));
             }
             let data = parse_response_json(&text)?;
 
             if let Some(captcha_url) = data["captchaUrl"].as_str() {
                 if captcha_failures >= Self::MAX_STEP1_CAPTCHA_FAILURES {
-                    return Err(anyhow!(
+                    return Err(Error::login(format!(
                         "Login step 1 failed: Too many captcha attempts ({})",
                         Self::MAX_STEP1_CAPTCHA_FAILURES
-                    ));
+                    )));
                 }
                 captcha_failures += 1;
                 captcha = Some(
@@ -542,23 +878,25 @@ impl MiCloudProtocol {
                 ("hash", password_md5.to_string()),
                 ("_json", "true".to_string()),
                 ("sid", "xiaomiio".to_string()),
-                ("callback", "https://sts.api.io.mi.com/sts".to_string()),
+                ("callback", self.urls.sts_callback()),
                 ("qs", "%3Fsid%3Dxiaomiio%26_json%3Dtrue".to_string()),
                 ("_sign", current_sign.clone()),
                 ("user", username.to_string()),
             ];
             form_data.extend(captcha_code.as_deref().map(|c| ("captCode", c.to_string())));
 
-            let resp = client
-                .post(self.urls.login_step2.clone())
-                .form(&form_data)
-                .header(header::USER_AGENT, &self.user_agent)
-                .send()
+            let resp = self
+                .send(
+                    client
+                        .post(self.urls.login_step2_url())
+                        .form(&form_data)
+                        .header(header::USER_AGENT, &self.user_agent),
+                )
                 .await?;
             let status = resp.status();
             let text = resp.text().await?;
             if !status.is_success() {
-                return Err(anyhow!("Login step 2 failed: HTTP {}", status));
+                return Err(Error::Http(status.as_u16()));
             }
 
             let data = parse_response_json(&text)?;
@@ -571,7 +909,7 @@ impl MiCloudProtocol {
                 }
                 consecutive_captcha_solves += 1;
                 if consecutive_captcha_solves >= Self::MAX_CONSECUTIVE_CAPTCHA_SOLVES {
-                    return Err(anyhow!(
+                    return Err(Error::login(
                         "Login failed: Too many captcha challenges. The password may be incorrect or the account may be locked. Please verify your credentials and try again later.",
                     ));
                 }
@@ -590,10 +928,10 @@ impl MiCloudProtocol {
             if let Some(ssecurity) = data["ssecurity"].as_str() {
                 let user_id = data["userId"]
                     .as_i64()
-                    .ok_or_else(|| anyhow!("Login step 2 failed: No 'userId'"))?;
+                    .ok_or_else(|| Error::login("Login step 2 failed: No 'userId'"))?;
                 let location = data["location"]
                     .as_str()
-                    .ok_or_else(|| anyhow!("Login step 2 failed: No 'location'"))?;
+                    .ok_or_else(|| Error::login("Login step 2 failed: No 'location'"))?;
                 return Ok(LoginStep2Result::SuccessWithLocation {
                     ssecurity: ssecurity.to_string(),
                     user_id,
@@ -629,27 +967,25 @@ impl MiCloudProtocol {
                     }
                     continue;
                 }
-                Some(c) => {
-                    if let Some(msg) = login_codes::error_message(c) {
-                        return Err(anyhow!("{msg}"));
-                    }
+                Some(c) if login_codes::error_message(c).is_some() => {
+                    return Err(Error::InvalidCredentials(c));
                 }
-                None => {}
+                _ => {}
             }
 
-            return Err(anyhow!("Login step 2 failed: No 'ssecurity', 'notificationUrl', or 'captchaUrl' in response. Response: {}", data));
+            return Err(Error::login(format!("Login step 2 failed: No 'ssecurity', 'notificationUrl', or 'captchaUrl' in response. Response: {}", data)));
         }
     }
 
     async fn login_step3(&self, client: &Client, url: String) -> Result<String> {
-        client
-            .get(url)
-            .send()
+        self.send(client.get(url))
             .await?
             .cookies()
             .find(|c| c.name() == "serviceToken")
             .map(|c| c.value().to_string())
-            .ok_or_else(|| anyhow!("Login Step 3: 'serviceToken' cookie not found in the response"))
+            .ok_or_else(|| {
+                Error::login("Login Step 3: 'serviceToken' cookie not found in the response")
+            })
     }
 
     async fn request_captcha_solve(&self, captcha_b64_data_url: String) -> Result<String> {
@@ -668,16 +1004,16 @@ impl MiCloudProtocol {
                 }
                 Ok(ChallengeSolution::Cancel) => {
                     debug!("request_captcha_solve: user cancelled captcha");
-                    Err(anyhow!("Captcha cancelled by user"))
+                    Err(Error::CaptchaCancelled)
                 }
                 Err(e) => {
                     debug!("request_captcha_solve: error {:?}", e);
-                    Err(anyhow!("Captcha error: {:?}", e))
+                    Err(e)
                 }
             }
         } else {
-            Err(anyhow!(
-                "Captcha required but no captcha handler is configured"
+            Err(Error::login(
+                "Captcha required but no captcha handler is configured",
             ))
         }
     }
@@ -703,28 +1039,29 @@ impl MiCloudProtocol {
         notification_url: String,
     ) -> Result<Handle2FaResult> {
         // Step 1: Visit notificationUrl to initialize the session and get initial cookies.
-        client
-            .get(&notification_url)
-            .header(header::USER_AGENT, self.user_agent.to_string())
-            .send()
-            .await?;
+        self.send(
+            client
+                .get(&notification_url)
+                .header(header::USER_AGENT, self.user_agent.to_string()),
+        )
+        .await?;
 
         // Step 2: Extract the 'context' parameter from the notification URL.
-        let parsed_url = Url::parse(&notification_url)?;
+        let parsed_url = Url::parse(&notification_url).map_err(Error::decode)?;
         let context = parsed_url
             .query_pairs()
             .find_map(|(key, value)| (key == "context").then(|| value.into_owned()))
-            .with_context(|| "2FA Flow: Could not find 'context' parameter in notification URL")?;
+            .ok_or_else(|| {
+                Error::login("2FA Flow: Could not find 'context' parameter in notification URL")
+            })?;
 
         // Step 3: Fetch identity options to get the 'identity_session' cookie.
-        let list_res = client
-            .get("https://account.xiaomi.com/identity/list")
-            .query(&[
+        let list_res = self
+            .send(client.get(self.urls.account_url("/identity/list")).query(&[
                 ("sid", "xiaomiio"),
                 ("context", &context),
                 ("supportedMask", "0"),
-            ])
-            .send()
+            ]))
             .await?
             .error_for_status()?;
 
@@ -739,44 +1076,53 @@ impl MiCloudProtocol {
             .and_then(|opts| opts.as_array())
             .map(|opts| opts.iter().filter_map(|v| v.as_i64()).collect::<Vec<i64>>())
             .ok_or_else(|| {
-                anyhow!("2FA Flow: Could not find 'options' array in identity/list response")
+                Error::login("2FA Flow: Could not find 'options' array in identity/list response")
             })?;
 
         if options.is_empty() {
-            return Err(anyhow!(
+            return Err(Error::login(format!(
                     "2FA Flow: Visit <a href=\"{}\" target=\"_blank\"><strong>link</strong></a> to configure account",
                     notification_url
-                ));
+                )));
         }
 
         // let the user select the option?
-        let flag = list_json["flag"]
-            .as_i64()
-            .ok_or_else(|| anyhow!("2FA Flow: 'flag' not found in 'identity/list' response"))?;
+        let flag = list_json["flag"].as_i64().ok_or_else(|| {
+            Error::login("2FA Flow: 'flag' not found in 'identity/list' response")
+        })?;
 
         // Step 4: Request the 2FA code to be sent to the user's email.
-        let dc1 = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
-        let send_ticket_url = if flag == 4 {
-            "https://account.xiaomi.com/identity/auth/sendPhoneTicket"
+        let dc1 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let send_ticket_url = self.urls.account_url(if flag == 4 {
+            "/identity/auth/sendPhoneTicket"
         } else {
-            "https://account.xiaomi.com/identity/auth/sendEmailTicket"
-        };
-        let send_ticket_res = client
-            .post(send_ticket_url)
-            .header(header::USER_AGENT, self.user_agent.to_string())
-            .query(&[("_dc", &dc1.to_string())])
-            .form(&[("retry", "0"), ("icode", ""), ("_json", "true")])
-            .send()
+            "/identity/auth/sendEmailTicket"
+        });
+        let send_ticket_res = self
+            .send(
+                client
+                    .post(&send_ticket_url)
+                    .header(header::USER_AGENT, self.user_agent.to_string())
+                    .query(&[("_dc", &dc1.to_string())])
+                    .form(&[("retry", "0"), ("icode", ""), ("_json", "true")]),
+            )
             .await?;
 
         let send_ticket_text = send_ticket_res.text().await?;
-        let send_ticket_json = parse_response_json(&send_ticket_text)
-            .with_context(|| format!("2FA Flow: Failed to parse {} response", send_ticket_url))?;
+        let send_ticket_json = parse_response_json(&send_ticket_text).map_err(|e| {
+            Error::decode(format!(
+                "2FA Flow: Failed to parse {} response: {}",
+                send_ticket_url, e
+            ))
+        })?;
         if send_ticket_json.get("code").and_then(Value::as_i64) != Some(0) {
-            return Err(anyhow!(
+            return Err(Error::login(format!(
                 "2FA Flow: Failed to send 2FA code. Response: {}",
                 send_ticket_text
-            ));
+            )));
         }
 
         // Step 5: Create a separate client WITHOUT automatic redirects.
@@ -798,38 +1144,41 @@ impl MiCloudProtocol {
                     .await
                 {
                     Ok(ChallengeSolution::Solved(code)) => code,
-                    _ => return Err(anyhow!("2FA challenge was canceled by the user")),
+                    _ => return Err(Error::TwoFactorCancelled),
                 }
             } else {
-                return Err(anyhow!("2FA is required, but no 2FA handler is configured"));
+                return Err(Error::login(
+                    "2FA is required, but no 2FA handler is configured",
+                ));
             };
 
             // Step 7: Submit the user-provided code for verification.
-            let dc2 = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
-            let verify_url = if flag == 4 {
-                "https://account.xiaomi.com/identity/auth/verifyPhone"
+            let dc2 = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            let verify_url = self.urls.account_url(if flag == 4 {
+                "/identity/auth/verifyPhone"
             } else {
-                "https://account.xiaomi.com/identity/auth/verifyEmail"
-            };
-            let verify_res = client
-                .post(verify_url)
-                .header(header::USER_AGENT, self.user_agent.to_string())
-                .query(&[("_dc", &dc2.to_string())])
-                .form(&[
-                    ("_flag", &flag.to_string()),
-                    ("ticket", &code),
-                    ("trust", &"false".to_string()),
-                    ("_json", &"true".to_string()),
-                ])
-                .send()
+                "/identity/auth/verifyEmail"
+            });
+            let verify_res = self
+                .send(
+                    client
+                        .post(&verify_url)
+                        .header(header::USER_AGENT, self.user_agent.to_string())
+                        .query(&[("_dc", &dc2.to_string())])
+                        .form(&[
+                            ("_flag", &flag.to_string()),
+                            ("ticket", &code),
+                            ("trust", &"false".to_string()),
+                            ("_json", &"true".to_string()),
+                        ]),
+                )
                 .await?;
 
             if !verify_res.status().is_success() {
-                return Err(anyhow!(
-                    "2FA Flow: verify url {} failed with status: {}",
-                    verify_url,
-                    verify_res.status()
-                ));
+                return Err(Error::Http(verify_res.status().as_u16()));
             }
 
             let headers = verify_res.headers().clone();
@@ -854,9 +1203,11 @@ impl MiCloudProtocol {
                 }
 
                 if finish_loc.is_none() {
-                    let re = Regex::new(
-                        r#"https://account\.xiaomi\.com/identity/result/check\?[^"']+"#,
-                    )?;
+                    let re = Regex::new(&format!(
+                        r#"{}\?[^"']+"#,
+                        regex::escape(&self.urls.account_url("/identity/result/check"))
+                    ))
+                    .map_err(Error::decode)?;
                     finish_loc = re.find(&res_text).map(|m| m.as_str().to_string());
                 }
 
@@ -864,14 +1215,16 @@ impl MiCloudProtocol {
                 let finish_loc = if let Some(loc) = finish_loc {
                     loc
                 } else {
-                    let fallback_res = no_redirect_client
-                        .get("https://account.xiaomi.com/identity/result/check")
-                        .query(&[
-                            ("sid", "xiaomiio"),
-                            ("context", &context),
-                            ("_locale", "en_US"),
-                        ])
-                        .send()
+                    let fallback_res = self
+                        .send(
+                            no_redirect_client
+                                .get(self.urls.account_url("/identity/result/check"))
+                                .query(&[
+                                    ("sid", "xiaomiio"),
+                                    ("context", &context),
+                                    ("_locale", "en_US"),
+                                ]),
+                        )
                         .await?;
 
                     fallback_res
@@ -879,34 +1232,34 @@ impl MiCloudProtocol {
                     .get(header::LOCATION)
                     .and_then(|v| v.to_str().ok())
                     .map(String::from)
-                    .context("2FA Flow: Could not determine next step URL (finish_loc) from any source")?
+                    .ok_or_else(|| Error::login("2FA Flow: Could not determine next step URL (finish_loc) from any source"))?
                 };
 
                 // Step 9: Handle the intermediate redirect via 'result/check' if necessary.
                 let end_url = if finish_loc.contains("identity/result/check") {
-                    no_redirect_client
-                        .get(&finish_loc)
-                        .send()
+                    self.send(no_redirect_client.get(&finish_loc))
                         .await?
                         .headers()
                         .get(header::LOCATION)
                         .and_then(|v| v.to_str().ok())
                         .map(String::from)
-                        .context(
-                            "2FA Flow: Missing 'Location' header after 'result/check' redirect",
-                        )?
+                        .ok_or_else(|| {
+                            Error::login(
+                                "2FA Flow: Missing 'Location' header after 'result/check' redirect",
+                            )
+                        })?
                 } else {
                     finish_loc
                 };
 
                 // Step 10: Request the 'end_url' and handle the optional "tips page" redirect.
-                let first_res = no_redirect_client.get(&end_url).send().await?;
+                let first_res = self.send(no_redirect_client.get(&end_url)).await?;
                 let first_status = first_res.status();
                 let mut res_headers = first_res.headers().clone();
                 let mut res_text = first_res.text().await?;
 
                 if first_status.is_success() && res_text.contains("Xiaomi Account - Tips") {
-                    let second_res = no_redirect_client.get(&end_url).send().await?;
+                    let second_res = self.send(no_redirect_client.get(&end_url)).await?;
                     res_headers = second_res.headers().clone();
                     res_text = second_res.text().await?;
                 }
@@ -917,9 +1270,11 @@ impl MiCloudProtocol {
                     .and_then(|v| v.to_str().ok())
                     .and_then(|s| serde_json::from_str::<Value>(s).ok())
                     .and_then(|json| json["ssecurity"].as_str().map(String::from))
-                    .context(
-                        "2FA Flow: Could not extract 'ssecurity' from 'extension-pragma' header",
-                    )?;
+                    .ok_or_else(|| {
+                        Error::login(
+                            "2FA Flow: Could not extract 'ssecurity' from 'extension-pragma' header",
+                        )
+                    })?;
 
                 // Step 12: Reliably extract the STS URL from headers or response body.
                 let mut sts_url_str = res_headers
@@ -928,20 +1283,21 @@ impl MiCloudProtocol {
                     .map(String::from);
 
                 if sts_url_str.is_none() {
-                    if let Some(idx) = res_text.find("https://sts.api.io.mi.com/sts") {
+                    if let Some(idx) = res_text.find(&self.urls.sts_callback()) {
                         let end_idx = res_text[idx..].find('"').unwrap_or(300) + idx;
                         sts_url_str = Some(res_text[idx..end_idx].to_string());
                     }
                 }
 
-                let sts_url = sts_url_str
-                    .context("2FA Flow: Could not find STS redirect URL in headers or body")?;
+                let sts_url = sts_url_str.ok_or_else(|| {
+                    Error::login("2FA Flow: Could not find STS redirect URL in headers or body")
+                })?;
 
                 // Step 14: Visit the STS URL to get the 'serviceToken' cookie.
-                client.get(&sts_url).send().await?;
+                self.send(client.get(&sts_url)).await?;
 
                 // Step 14: Extract the final 'serviceToken' and 'userId' from the cookie jar.
-                let sts_url_parsed = "https://sts.api.io.mi.com".parse::<Url>()?;
+                let sts_url_parsed = self.urls.sts.parse::<Url>().map_err(Error::decode)?;
                 let service_token = jar
                     .cookies(&sts_url_parsed)
                     .and_then(|c| {
@@ -952,10 +1308,12 @@ impl MiCloudProtocol {
                             .split(';')
                             .find_map(|p| p.trim().strip_prefix("serviceToken=").map(String::from))
                     })
-                    .context("2FA Flow: Could not find 'serviceToken' cookie")?;
+                    .ok_or_else(|| {
+                        Error::login("2FA Flow: Could not find 'serviceToken' cookie")
+                    })?;
 
                 let user_id_str = jar
-                    .cookies(&"https://account.xiaomi.com/".parse::<Url>()?)
+                    .cookies(&self.urls.account.parse::<Url>().map_err(Error::decode)?)
                     .and_then(|c| {
                         c.to_str()
                             .ok()
@@ -964,9 +1322,9 @@ impl MiCloudProtocol {
                             .split(';')
                             .find_map(|p| p.trim().strip_prefix("userId=").map(String::from))
                     })
-                    .context("2FA Flow: Could not find 'userId' cookie")?;
+                    .ok_or_else(|| Error::login("2FA Flow: Could not find 'userId' cookie"))?;
 
-                let user_id = user_id_str.parse::<i64>()?;
+                let user_id = user_id_str.parse::<i64>().map_err(Error::decode)?;
 
                 return Ok(Handle2FaResult::Success {
                     ssecurity,
@@ -977,10 +1335,10 @@ impl MiCloudProtocol {
                 error_message = "Incorrect code. Please try again.";
                 continue; // Prompt the user again.
             } else {
-                return Err(anyhow!(
+                return Err(Error::login(format!(
                     "2FA Flow: Verification failed with an unexpected error. Response: {}",
                     res_text
-                ));
+                )));
             }
         }
     }
@@ -999,53 +1357,125 @@ impl MiCloudProtocol {
         data: serde_json::Value,
         country: &str,
     ) -> Result<serde_json::Value> {
-        let client = Client::new();
+        self.request_with(Transport::Signed, path, data, country)
+            .await
+    }
 
-        if self.service_token.is_none() {
-            return Err(anyhow!("Request error: Not logged in"));
-        }
+    async fn request_with(
+        &self,
+        transport: Transport,
+        path: &str,
+        data: Value,
+        country: &str,
+    ) -> Result<Value> {
+        let credentials = self.credentials.get().ok_or(Error::NotLoggedIn)?;
 
         if !self.is_country_supported(country) {
-            return Err(anyhow!(
-                "Request error: Server Location {} is not supported",
-                country
-            ));
+            return Err(Error::UnsupportedCountry(country.to_string()));
         }
 
-        let params = json!({"data": data});
+        let res = self
+            .send_request(transport, &credentials, path, &data, country)
+            .await;
+        if !is_auth_expired(&res) {
+            return res;
+        }
+        debug!("[miio::request] {} rejected, serviceToken expired", path);
+        let credentials = self.relogin(&credentials).await?;
+        self.send_request(transport, &credentials, path, &data, country)
+            .await
+    }
+
+    async fn send_request(
+        &self,
+        transport: Transport,
+        credentials: &Credentials,
+        path: &str,
+        data: &Value,
+        country: &str,
+    ) -> Result<Value> {
+        let client = Client::new();
+
         let url = format!("{}{}", self.get_api_url(country), path);
         let nonce = self.generate_nonce();
-        let signed_nonce = self.signed_nonce(self.ssecurity.as_ref().unwrap(), &nonce);
-        let signature = self.generate_signature(path, &signed_nonce, &nonce, &params);
-        let body = json!({
-            "_nonce": nonce,
-            "data": data,
-            "signature": signature
-        });
+        let signed_nonce = self.signed_nonce(&credentials.ssecurity, &nonce);
+        let body = match transport {
+            Transport::Signed => {
+                let params = json!({"data": data});
+                let signature = self.generate_signature(path, &signed_nonce, &nonce, &params);
+                json!({
+                    "_nonce": nonce,
+                    "data": data,
+                    "signature": signature
+                })
+            }
+            Transport::Rc4 => {
+                self.generate_enc_params(path, &signed_nonce, &nonce, &credentials.ssecurity, data)?
+            }
+        };
 
         let body_as_query_string = object_to_query_string(&body);
 
-        let res = client
+        let mut req = client
             .post(&url)
             .header(header::USER_AGENT, self.user_agent.to_string())
             .header("x-xiaomi-protocal-flag-cli", "PROTOCAL-HTTP2")
             .header("mishop-client-id", "180100041079")
             .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-            .header(header::COOKIE, self.get_cookie())
-            .body(body_as_query_string)
-            .send()
-            .await
-            .with_context(|| "Failed to send request")?;
+            .header(header::COOKIE, self.get_cookie(credentials))
+            .body(body_as_query_string);
+        if transport == Transport::Rc4 {
+            req = req
+                .header("MIOT-ENCRYPT-ALGORITHM", "ENCRYPT-RC4")
+                .header(header::ACCEPT_ENCODING, "identity");
+        }
+        let api = ApiCall {
+            data,
+            signed_nonce: (transport == Transport::Rc4).then_some(signed_nonce.as_str()),
+        };
+        let res = self.send_api(req, Some(api)).await?;
 
         if !res.status().is_success() {
-            return Err(anyhow!(
-                "Request error: Status {}, {:#?}",
-                res.status(),
-                res
-            ));
+            debug!("[miio::request] {} failed: {:#?}", path, res);
+            return Err(Error::Http(res.status().as_u16()));
+        }
+
+        match transport {
+            Transport::Signed => Ok(res.json().await?),
+            Transport::Rc4 => {
+                let body = res.text().await?;
+                // Errors are sometimes returned unencrypted
+                if let Ok(plain @ Value::Object(_)) = serde_json::from_str(&body) {
+                    return Ok(plain);
+                }
+                Ok(serde_json::from_str(
+                    &self.decrypt_rc4(&signed_nonce, &body)?,
+                )?)
+            }
         }
+    }
 
-        res.json().await.with_context(|| "Failed to parse response")
+    /// Logs in again with the stored password hash after `expired` was rejected.
+    /// Captcha and 2FA handlers are only called if the cloud asks for them.
+    /// Only a protocol that went through `login` has the hash; restored
+    /// sessions get `Error::SessionExpired`.
+    async fn relogin(&self, expired: &Credentials) -> Result<Credentials> {
+        let _guard = self.relogin_lock.lock().await;
+        // A concurrent request may have refreshed the session while this one waited
+        let current = self.credentials.get().ok_or(Error::NotLoggedIn)?;
+        if current.service_token != expired.service_token {
+            return Ok(current);
+        }
+        let (Some(username), Some(password_md5)) = (&self.username, &self.password_md5) else {
+            return Err(Error::SessionExpired);
+        };
+
+        let credentials = self.authenticate(username, password_md5).await?;
+        self.credentials.set(Some(credentials.clone()));
+        if let (Some(handler), Some(session)) = (&self.session_handler, self.get_session()) {
+            handler(session);
+        }
+        Ok(credentials)
     }
 
     fn generate_nonce(&self) -> String {
@@ -1059,17 +1489,21 @@ impl MiCloudProtocol {
             / 60;
         hex::decode_to_slice(random_hex, &mut buf[0..8]).expect("Decoding failed");
         buf[8..].copy_from_slice(&timestamp.to_be_bytes());
-        base64::encode(buf)
+        STANDARD.encode(buf)
     }
 
     fn signed_nonce(&self, secret: &str, nonce: &str) -> String {
-        let secret_bytes = base64::decode(secret).expect("Failed to decode secret from base64");
-        let nonce_bytes = base64::decode(nonce).expect("Failed to decode nonce from base64");
+        let secret_bytes = STANDARD
+            .decode(secret)
+            .expect("Failed to decode secret from base64");
+        let nonce_bytes = STANDARD
+            .decode(nonce)
+            .expect("Failed to decode nonce from base64");
         let mut hasher = Sha256::new();
         hasher.update(&secret_bytes);
         hasher.update(&nonce_bytes);
         let hash_result = hasher.finalize();
-        base64::encode(hash_result)
+        STANDARD.encode(hash_result)
     }
 
     fn generate_signature(
@@ -1100,32 +1534,91 @@ impl MiCloudProtocol {
         }
         let exps_str = exps.join("&");
 
-        let key = base64::decode(signed_nonce).unwrap();
+        let key = STANDARD.decode(signed_nonce).unwrap();
         let mut signing_key = Hmac::<Sha256>::new_varkey(&key).unwrap();
         signing_key.update(exps_str.as_bytes());
         let result = signing_key.finalize().into_bytes();
-        base64::encode(result)
+        STANDARD.encode(result)
     }
 
-    fn get_api_url(&self, country: &str) -> String {
-        match self.urls.to_json().get(country) {
-            Some(url) => url.as_str().unwrap().to_string(),
-            None => self.urls.cn.clone(),
+    /// RC4 keyed with the signed nonce, dropping the first 1024 bytes of keystream.
+    fn rc4_apply(&self, signed_nonce: &str, buf: &mut [u8]) -> Result<()> {
+        let key = STANDARD.decode(signed_nonce).map_err(Error::decode)?;
+        let mut cipher = Rc4::<U32>::new_from_slice(&key).map_err(Error::decode)?;
+        cipher.apply_keystream(&mut [0u8; 1024]);
+        cipher.apply_keystream(buf);
+        Ok(())
+    }
+
+    fn encrypt_rc4(&self, signed_nonce: &str, payload: &str) -> Result<String> {
+        let mut buf = payload.as_bytes().to_vec();
+        self.rc4_apply(signed_nonce, &mut buf)?;
+        Ok(STANDARD.encode(buf))
+    }
+
+    fn decrypt_rc4(&self, signed_nonce: &str, payload: &str) -> Result<String> {
+        let mut buf = STANDARD.decode(payload.trim()).map_err(Error::decode)?;
+        self.rc4_apply(signed_nonce, &mut buf)?;
+        String::from_utf8(buf).map_err(Error::decode)
+    }
+
+    /// Base64 SHA-1 over the method, path, params in order and signed nonce.
+    fn generate_enc_signature(
+        &self,
+        path: &str,
+        signed_nonce: &str,
+        params: &[(&str, String)],
+    ) -> String {
+        let mut exps = vec!["POST".to_string(), path.to_string()];
+        exps.extend(params.iter().map(|(k, v)| format!("{k}={v}")));
+        exps.push(signed_nonce.to_string());
+        STANDARD.encode(crypto_hash::digest(
+            Algorithm::SHA1,
+            exps.join("&").as_bytes(),
+        ))
+    }
+
+    /// Form params of an RC4 request: `data` and its `rc4_hash__`, both
+    /// encrypted, plus a `signature` over the encrypted values.
+    fn generate_enc_params(
+        &self,
+        path: &str,
+        signed_nonce: &str,
+        nonce: &str,
+        ssecurity: &str,
+        data: &Value,
+    ) -> Result<Value> {
+        let mut params = vec![("data", serde_value_to_string(data))];
+        let rc4_hash = self.generate_enc_signature(path, signed_nonce, &params);
+        params.push(("rc4_hash__", rc4_hash));
+        for (_, value) in params.iter_mut() {
+            *value = self.encrypt_rc4(signed_nonce, value)?;
         }
+        let signature = self.generate_enc_signature(path, signed_nonce, &params);
+        Ok(json!({
+            "data": params[0].1,
+            "rc4_hash__": params[1].1,
+            "signature": signature,
+            "ssecurity": ssecurity,
+            "_nonce": nonce,
+        }))
     }
 
-    fn get_cookie(&self) -> String {
+    fn get_api_url(&self, country: &str) -> String {
+        self.urls.api(country).to_string()
+    }
+
+    fn get_cookie(&self, credentials: &Credentials) -> String {
         let mut cookies: Vec<String> = vec![];
 
         cookies.push("sdkVersion=accountsdk-18.8.15".to_string());
         cookies.push(format!("deviceId={}", self.client_id));
-        if let Some(user_id) = &self.user_id.as_ref() {
-            cookies.push(format!("userId={}", user_id));
-        }
-        if let Some(token) = &self.service_token.as_ref() {
-            cookies.push(format!("serviceToken={}", token));
-            cookies.push(format!("yetAnotherServiceToken={}", token));
-        }
+        cookies.push(format!("userId={}", credentials.user_id));
+        cookies.push(format!("serviceToken={}", credentials.service_token));
+        cookies.push(format!(
+            "yetAnotherServiceToken={}",
+            credentials.service_token
+        ));
         cookies.push(format!("locale={}", self.locale));
         cookies.push("channel=MI_APP_STORE".to_string());
 
@@ -1148,6 +1641,9 @@ mod tests {
 
     extern crate tokio;
     use super::*;
+    use crate::test_util::{device_json, logged_in, mock_protocol};
+    use miio_mock::{MockAccount, MockCloud, TwoFactor, CAPTCHA_IMAGE};
+    use tokio::sync::mpsc;
 
     #[test]
     fn signed_nonce() {
@@ -1171,6 +1667,65 @@ mod tests {
         assert_eq!(result, expect);
     }
 
+    #[test]
+    fn encrypt_rc4() {
+        let mi: MiCloudProtocol = MiCloudProtocol::new();
+        let signed_nonce = "zq3TaSr/VwnmvvWwMTAEMAuzxs2gLgP6uFJS7bBtWKo=";
+        let result = mi
+            .encrypt_rc4(
+                signed_nonce,
+                r#"{"getHuamiDevices":0,"getVirtualModel":false}"#,
+            )
+            .unwrap();
+        let expect = "9UFv+SgQZtBB0O3IQ15InU4dVL+LyGPZtDGJ5BybdQ9KWBXV5f/kfHSkiDPI";
+        assert_eq!(result, expect);
+
+        let result = mi
+            .decrypt_rc4(
+                signed_nonce,
+                "9UFr8zg9MYsclYvAUERYmVpaTLWFhW+e7EWS8xubeBclDQqS5bStbjfyoAvIzg==",
+            )
+            .unwrap();
+        assert_eq!(result, r#"{"code":0,"message":"ok","result":{"list":[]}}"#);
+    }
+
+    #[test]
+    fn generate_enc_signature() {
+        let mi: MiCloudProtocol = MiCloudProtocol::new();
+        let result = mi.generate_enc_signature(
+            "/home/device_list",
+            "zq3TaSr/VwnmvvWwMTAEMAuzxs2gLgP6uFJS7bBtWKo=",
+            &[(
+                "data",
+                r#"{"getHuamiDevices":0,"getVirtualModel":false}"#.to_string(),
+            )],
+        );
+        let expect = "8PpVhTodjvlHEFGfk5cSOGmt3TA=";
+        assert_eq!(result, expect);
+    }
+
+    #[test]
+    fn generate_enc_params() {
+        let mi: MiCloudProtocol = MiCloudProtocol::new();
+        let result = mi
+            .generate_enc_params(
+                "/home/device_list",
+                "zq3TaSr/VwnmvvWwMTAEMAuzxs2gLgP6uFJS7bBtWKo=",
+                "BejIOTLgvecBs9sT",
+                "9wR21gAtfAyn+KDX1ok/Iw==",
+                &json!({"getVirtualModel":false,"getHuamiDevices":0}),
+            )
+            .unwrap();
+        let expect = json!({
+            "data": "9UFv+SgQZtBB0O3IQ15InU4dVL+LyGPZtDGJ5BybdQ9KWBXV5f/kfHSkiDPI",
+            "rc4_hash__": "tjN4yjQMfNVGz8XlcHFsnlYKDdzorWnI8zOhqw==",
+            "signature": "XjoAqKZtr/TK+TMgV9JWbSuCdrw=",
+            "ssecurity": "9wR21gAtfAyn+KDX1ok/Iw==",
+            "_nonce": "BejIOTLgvecBs9sT",
+        });
+        assert_eq!(result, expect);
+    }
+
     #[test]
     fn parse_response_json() {
         let res = super::parse_response_json("&&&START&&&{\"_nonce\":\"BejIOTLgvecBs9sT\",\"data\":{\"getVirtualModel\":false,\"getHuamiDevices\":0}}").unwrap();
@@ -1179,6 +1734,48 @@ mod tests {
         assert_eq!(res["data"]["getHuamiDevices"], 0);
     }
 
+    #[test]
+    fn parse_device_tolerantly() {
+        let device: Device = serde_json::from_value(json!({
+            "did": 123456789,
+            "name": "Lamp",
+            "model": "yeelink.light.color1",
+            "isOnline": 1,
+            "localip": null,
+            "permitLevel": "36",
+            "uid": 1234567890,
+            "extra": {
+                "isSetPincode": 0,
+                "fw_version": "1.4.1_176",
+                "needVerifyCode": "1",
+                "mcu_version": "0030",
+                "pincodeType": 0
+            },
+            "orderTime": 1700000000,
+            "spec_type": "urn:miot-spec-v2:device:light:0000A001:yeelink-color1:1"
+        }))
+        .unwrap();
+        assert_eq!(device.did(), "123456789");
+        assert!(device.is_online());
+        assert_eq!(device.local_ip(), "");
+        assert_eq!(device.permit_level(), 36);
+        assert_eq!(device.extra().fw_version, "1.4.1_176");
+        assert_eq!(device.extra().need_verify_code, 1);
+        assert_eq!(device.extra().other_fields["pincodeType"], 0);
+        assert_eq!(device.other_fields()["orderTime"], 1700000000);
+
+        let value = serde_json::to_value(&device).unwrap();
+        assert_eq!(value["isOnline"], true);
+        assert_eq!(value["permitLevel"], 36);
+        assert_eq!(value["spec_type"], device.other_fields()["spec_type"]);
+        assert_eq!(value["extra"]["pincodeType"], 0);
+        let again: Device = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&again).unwrap(), value);
+
+        let device: Device = serde_json::from_value(json!({ "did": "1", "extra": [] })).unwrap();
+        assert_eq!(device.extra(), &DeviceExtra::default());
+    }
+
     #[test]
     fn serde_value_to_string() {
         let obj = json!({
@@ -1203,21 +1800,281 @@ mod tests {
         assert_eq!(result, expect);
     }
 
-    // #[tokio::test]
+    #[test]
+    fn session_roundtrip() {
+        let session = MiCloudSession {
+            user_id: "123456".to_string(),
+            ssecurity: "9wR21gAtfAyn+KDX1ok/Iw==".to_string(),
+            service_token: "token".to_string(),
+            client_id: "android_client".to_string(),
+            country: "de".to_string(),
+            username: Some("user@example.com".to_string()),
+        };
+        let saved = serde_json::to_string(&session).unwrap();
+        let mi = MiCloudProtocol::from_session(serde_json::from_str(&saved).unwrap());
+
+        assert!(mi.is_logged_in());
+        assert_eq!(mi.get_session(), Some(session));
+        assert!(mi
+            .get_cookie(&mi.credentials.get().unwrap())
+            .contains("serviceToken=token"));
+        assert!(mi
+            .get_cookie(&mi.credentials.get().unwrap())
+            .contains("deviceId=android_client"));
+        assert!(MiCloudProtocol::new().get_session().is_none());
+    }
+
+    #[tokio::test]
+    async fn clone_shares_challenge_state() {
+        let mut mi = MiCloudProtocol::new();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = std::sync::Mutex::new(Some(tx));
+        mi._set_captcha_handler(Box::new(move |url| {
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send(url);
+            }
+        }));
+
+        let clone = mi.clone();
+        let pending =
+            tokio::spawn(async move { clone.request_captcha_solve("data:".to_string()).await });
+        assert_eq!(rx.await.unwrap(), "data:");
+        mi.captcha_solve("1234").await;
+        assert_eq!(pending.await.unwrap().unwrap(), "1234");
+    }
+
+    #[tokio::test]
     async fn e2e() {
-        let mut mi: MiCloudProtocol = MiCloudProtocol::new();
-        mi._override_urls(UrlsConfig {
-            cn: "http://localhost:3000".to_string(),
-            de: "http://localhost:3000".to_string(),
-            ru: "http://localhost:3000".to_string(),
-            sg: "http://localhost:3000".to_string(),
-            tw: "http://localhost:3000".to_string(),
-            us: "http://localhost:3000".to_string(),
-            i2: "http://localhost:3000".to_string(),
-            login_step1: "http://localhost:3000/step1".to_string(),
-            login_step2: "http://localhost:3000/step2".to_string(),
+        let mock = MockCloud::start(MockAccount::default()).await;
+        mock.state().devices = vec![device_json("1", json!({})), device_json("2", json!({}))];
+        mock.state().responses.insert(
+            "/home/rpc/1".to_string(),
+            json!({ "code": 0, "message": "ok", "result": ["on"] }),
+        );
+
+        let mi = logged_in(&mock).await;
+        let session = mi.get_session().unwrap();
+        assert_eq!(session.user_id, "1234567890");
+        assert_eq!(session.service_token, "mock-service-token");
+
+        assert_eq!(mi.get_devices(None, None).await.unwrap().len(), 2);
+        let device = mi.get_device("2", Some("de")).await.unwrap();
+        assert_eq!(device[0].did, "2");
+        let result = mi
+            .call_device("1", "get_prop", Some(json!(["power"])), None)
+            .await
+            .unwrap();
+        assert_eq!(result, json!(["on"]));
+
+        let state = mock.state();
+        assert_eq!(
+            state.paths(),
+            vec![
+                "/pass/serviceLogin",
+                "/pass/serviceLoginAuth2",
+                "/sts",
+                "/app/home/device_list",
+                "/app/home/device_list",
+                "/app/home/rpc/1",
+            ]
+        );
+        assert_eq!(
+            state.requests[5].data,
+            Some(json!({ "method": "get_prop", "params": ["power"] }))
+        );
+    }
+
+    #[tokio::test]
+    async fn e2e_rc4() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        mock.state().devices = vec![device_json("1", json!({})), device_json("2", json!({}))];
+        mock.state().responses.insert(
+            "/v2/homeroom/gethome".to_string(),
+            json!({ "code": 0, "message": "ok", "result": { "homelist": [] } }),
+        );
+        let mi = logged_in(&mock).await;
+
+        let res = mi
+            .call_api(
+                "/home/device_list",
+                json!({ "dids": ["2"] }),
+                Transport::Rc4,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res["result"]["list"][0]["did"], "2");
+        let res = mi
+            .call_api("/v2/homeroom/gethome", json!({}), Transport::Rc4, None)
+            .await
+            .unwrap();
+        assert_eq!(res["result"], json!({ "homelist": [] }));
+
+        let state = mock.state();
+        let recorded = state.requests.last().unwrap();
+        assert_eq!(recorded.path, "/app/v2/homeroom/gethome");
+        assert_eq!(recorded.data, Some(json!({})));
+    }
+
+    #[tokio::test]
+    async fn e2e_login_errors() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        let mut mi = mock_protocol(&mock);
+        assert!(matches!(
+            mi.login("user@example.com", "wrong").await,
+            Err(Error::InvalidCredentials(login_codes::INVALID_CREDENTIAL))
+        ));
+        assert!(matches!(
+            mi.login("nobody@example.com", "password").await,
+            Err(Error::InvalidCredentials(login_codes::INVALID_USERNAME))
+        ));
+        assert!(!mi.is_logged_in());
+    }
+
+    #[tokio::test]
+    async fn e2e_captcha() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        mock.state().step1_captcha = Some("1111".to_string());
+        mock.state().step2_captcha = Some("2222".to_string());
+
+        let mut mi = mock_protocol(&mock);
+        let (tx, mut images) = mpsc::unbounded_channel();
+        mi._set_captcha_handler(Box::new(move |url| {
+            let _ = tx.send(url);
+        }));
+        let solver = mi.clone();
+        let answers = tokio::spawn(async move {
+            // The wrong step 2 code sends the flow back to step 1, which asks again
+            for code in ["1111", "0000", "1111", "2222"] {
+                let image = images.recv().await.unwrap();
+                assert_eq!(
+                    image,
+                    format!("data:image/jpeg;base64,{}", STANDARD.encode(CAPTCHA_IMAGE))
+                );
+                solver.captcha_solve(code).await;
+            }
+            images
+        });
+        mi.login("user@example.com", "password").await.unwrap();
+        assert!(mi.is_logged_in());
+
+        let mut images = answers.await.unwrap();
+        assert!(images.try_recv().is_err());
+        mock.state().step2_captcha = Some("3333".to_string());
+        let solver = mi.clone();
+        tokio::spawn(async move {
+            images.recv().await.unwrap();
+            solver.captcha_cancel().await;
+        });
+        assert!(matches!(
+            mi.login("user@example.com", "password").await,
+            Err(Error::CaptchaCancelled)
+        ));
+    }
+
+    #[tokio::test]
+    async fn e2e_two_factor() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        mock.state().two_factor = Some(TwoFactor {
+            flag: 8,
+            code: "123456".to_string(),
+        });
+
+        let mut mi = mock_protocol(&mock);
+        let (tx, mut prompts) = mpsc::unbounded_channel();
+        mi._set_two_factor_handler(Box::new(move |flag, error| {
+            let _ = tx.send((flag, error));
+        }));
+        let solver = mi.clone();
+        let answers = tokio::spawn(async move {
+            let mut seen = vec![];
+            for code in ["000000", "123456"] {
+                seen.push(prompts.recv().await.unwrap());
+                solver.two_factor_solve(code).await;
+            }
+            seen
         });
-        mi.login("username", "password").await.unwrap();
-        mi.get_devices(None, None).await.unwrap();
+        mi.login("user@example.com", "password").await.unwrap();
+
+        let seen = answers.await.unwrap();
+        assert_eq!(seen[0], ("8".to_string(), "".to_string()));
+        assert_eq!(seen[1].1, "Incorrect code. Please try again.");
+        let session = mi.get_session().unwrap();
+        assert_eq!(session.user_id, "1234567890");
+        assert_eq!(session.ssecurity, "9wR21gAtfAyn+KDX1ok/Iw==");
+        assert_eq!(session.service_token, "mock-service-token");
+        assert!(mock
+            .state()
+            .paths()
+            .contains(&"/identity/auth/sendEmailTicket"));
+    }
+
+    #[tokio::test]
+    async fn e2e_rejected_session() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        let mut mi = logged_in(&mock).await;
+
+        // A different ssecurity produces signatures the cloud rejects
+        let mut session = mi.get_session().unwrap();
+        session.ssecurity = "AAAAAAAAAAAAAAAAAAAAAA==".to_string();
+        mi.set_session(session);
+        assert!(matches!(
+            mi.get_devices(None, None).await,
+            Err(Error::Http(403))
+        ));
+    }
+
+    #[tokio::test]
+    async fn e2e_restored_session_expired() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        let mi = logged_in(&mock).await;
+        let mut session = mi.get_session().unwrap();
+
+        // A restored session has no password to log in again with
+        session.service_token = "expired".to_string();
+        let mut restored = MiCloudProtocol::from_session(session);
+        restored._override_urls(UrlsConfig::with_base_url(mock.base_url()));
+        assert!(matches!(
+            restored.get_devices(None, None).await,
+            Err(Error::SessionExpired)
+        ));
+        let logins = mock
+            .state()
+            .requests
+            .iter()
+            .filter(|r| r.path == "/pass/serviceLoginAuth2")
+            .count();
+        assert_eq!(logins, 1);
+    }
+
+    #[tokio::test]
+    async fn e2e_relogin() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        mock.state().devices = vec![device_json("1", json!({}))];
+        let mut mi = mock_protocol(&mock);
+        mi._set_captcha_handler(Box::new(|_| panic!("captcha was not required")));
+        let (tx, mut refreshed) = mpsc::unbounded_channel();
+        mi._set_session_handler(Box::new(move |session| {
+            let _ = tx.send(session);
+        }));
+        mi.login("user@example.com", "password").await.unwrap();
+
+        mock.state().account.service_token = "renewed-token".to_string();
+        let (a, b) = tokio::join!(mi.get_devices(None, None), mi.get_device("1", None));
+        assert_eq!(a.unwrap().len(), 1);
+        assert_eq!(b.unwrap().len(), 1);
+
+        // Both rejected requests share a single re-login
+        let logins = mock
+            .state()
+            .paths()
+            .iter()
+            .filter(|p| **p == "/pass/serviceLoginAuth2")
+            .count();
+        assert_eq!(logins, 2);
+        let session = refreshed.try_recv().unwrap();
+        assert_eq!(session.service_token, "renewed-token");
+        assert!(refreshed.try_recv().is_err());
+        assert_eq!(mi.get_session().unwrap(), session);
     }
 }
@@ -0,0 +1,144 @@
+//! MIoT spec (siid/piid/aiid) cloud endpoints.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{cloud_error, MiCloudProtocol, Result};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiotPropertyRequest {
+    pub did: String,
+    pub siid: u32,
+    pub piid: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiotSetPropertyRequest {
+    pub did: String,
+    pub siid: u32,
+    pub piid: u32,
+    pub value: Value,
+}
+
+/// Per-property result, `code` is `0` on success.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiotPropertyResult {
+    pub did: String,
+    pub siid: u32,
+    pub piid: u32,
+    pub code: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiotActionRequest {
+    pub did: String,
+    pub siid: u32,
+    pub aiid: u32,
+    #[serde(rename = "in", default)]
+    pub input: Vec<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiotActionResult {
+    #[serde(default)]
+    pub did: Option<String>,
+    #[serde(default)]
+    pub siid: Option<u32>,
+    #[serde(default)]
+    pub aiid: Option<u32>,
+    pub code: i64,
+    #[serde(default)]
+    pub out: Vec<Value>,
+}
+
+fn parse_miot_response<T: DeserializeOwned>(res: Value, fallback_msg: &str) -> Result<T> {
+    if !res["result"].is_null() {
+        return Ok(serde_json::from_value(res["result"].clone())?);
+    }
+    Err(cloud_error(&res, fallback_msg))
+}
+
+impl MiCloudProtocol {
+    /// Reads MIoT properties via `/miotspec/prop/get`.
+    pub async fn get_properties(
+        &self,
+        params: &[MiotPropertyRequest],
+        country: Option<&str>,
+    ) -> Result<Vec<MiotPropertyResult>> {
+        let country = country.unwrap_or(self.country.as_str());
+        let res = self
+            .request("/miotspec/prop/get", json!({ "params": params }), country)
+            .await?;
+        parse_miot_response(res, "MIoT get properties failed")
+    }
+
+    /// Writes MIoT properties via `/miotspec/prop/set`.
+    pub async fn set_properties(
+        &self,
+        params: &[MiotSetPropertyRequest],
+        country: Option<&str>,
+    ) -> Result<Vec<MiotPropertyResult>> {
+        let country = country.unwrap_or(self.country.as_str());
+        let res = self
+            .request("/miotspec/prop/set", json!({ "params": params }), country)
+            .await?;
+        parse_miot_response(res, "MIoT set properties failed")
+    }
+
+    /// Invokes a MIoT action via `/miotspec/action`.
+    pub async fn call_action(
+        &self,
+        params: &MiotActionRequest,
+        country: Option<&str>,
+    ) -> Result<MiotActionResult> {
+        let country = country.unwrap_or(self.country.as_str());
+        let res = self
+            .request("/miotspec/action", json!({ "params": params }), country)
+            .await?;
+        parse_miot_response(res, "MIoT action failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_property_results() {
+        let res = json!({
+            "code": 0,
+            "message": "",
+            "result": [
+                {"did": "123", "siid": 2, "piid": 1, "code": 0, "value": true, "updateTime": 1700000000},
+                {"did": "123", "siid": 2, "piid": 9, "code": -4003}
+            ]
+        });
+        let result: Vec<MiotPropertyResult> = parse_miot_response(res, "failed").unwrap();
+        assert_eq!(result[0].value, Some(json!(true)));
+        assert_eq!(result[1].code, -4003);
+        assert_eq!(result[1].value, None);
+    }
+
+    #[test]
+    fn parse_error_response() {
+        let res = json!({ "code": -8, "message": "data type not valid" });
+        let err = parse_miot_response::<Vec<MiotPropertyResult>>(res, "failed").unwrap_err();
+        assert_eq!(err.to_string(), "data type not valid");
+    }
+
+    #[test]
+    fn action_request_uses_in_key() {
+        let req = MiotActionRequest {
+            did: "123".to_string(),
+            siid: 3,
+            aiid: 1,
+            input: vec![json!(5)],
+        };
+        assert_eq!(
+            serde_json::to_value(req).unwrap(),
+            json!({"did": "123", "siid": 3, "aiid": 1, "in": [5]})
+        );
+    }
+}
@@ -0,0 +1,481 @@
+//! Periodic property polling with change events.
+//!
+//! A `Poller` only keeps track of what to poll and the last values, the
+//! protocol is passed to `poll_due`, so callers can keep it behind their own
+//! lock (e.g. an account registry) between polls. Each watch remembers the
+//! account it was made for, `due_accounts` tells which protocols are needed.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::{Error, MiCloudProtocol, MiotPropertyRequest, Result};
+
+/// A property to poll: a miIO `get_prop` name or a MIoT siid/piid pair.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum PropertyId {
+    Miot { siid: u32, piid: u32 },
+    Miio(String),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PropertyChange {
+    pub did: String,
+    pub property: PropertyId,
+    /// `None` on the first successful poll
+    pub old: Option<Value>,
+    pub new: Value,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum PollEvent {
+    Changed(PropertyChange),
+    /// A poll failed; the device is polled again after `retry_in_secs`,
+    /// doubling with every failure up to the poller's maximum backoff.
+    Offline {
+        did: String,
+        error: Error,
+        retry_in_secs: u64,
+    },
+    /// The first successful poll after `Offline`
+    Online {
+        did: String,
+    },
+}
+
+struct Watch {
+    /// User id of the account to poll with, `None` for the active one
+    account: Option<String>,
+    properties: Vec<PropertyId>,
+    values: HashMap<PropertyId, Value>,
+    failures: u32,
+    next_poll: Instant,
+}
+
+pub struct Poller {
+    interval: Duration,
+    max_backoff: Duration,
+    watches: Mutex<HashMap<String, Watch>>,
+    /// Wakes `wait_due` when the watch list changes
+    changed: Notify,
+}
+
+impl Poller {
+    pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+    /// Polls every watched device each `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Poller {
+            interval,
+            max_backoff: Self::DEFAULT_MAX_BACKOFF.max(interval),
+            watches: Mutex::new(HashMap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Upper bound of the delay between polls of an offline device.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff.max(self.interval);
+        self
+    }
+
+    /// Starts polling `properties` of `did` with `account`, replacing what was
+    /// watched for it before. The device is due right away.
+    pub fn watch(&self, did: &str, account: Option<&str>, properties: Vec<PropertyId>) {
+        let watch = Watch {
+            account: account.map(str::to_string),
+            properties,
+            values: HashMap::new(),
+            failures: 0,
+            next_poll: Instant::now(),
+        };
+        self.watches.lock().unwrap().insert(did.to_string(), watch);
+        self.changed.notify_one();
+    }
+
+    pub fn unwatch(&self, did: &str) {
+        self.watches.lock().unwrap().remove(did);
+        self.changed.notify_one();
+    }
+
+    pub fn watched(&self) -> Vec<String> {
+        self.watches.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// When the next device is due, `None` if nothing is watched.
+    pub fn next_due(&self) -> Option<Instant> {
+        let watches = self.watches.lock().unwrap();
+        watches.values().map(|w| w.next_poll).min()
+    }
+
+    /// Accounts with at least one device due, each listed once.
+    pub fn due_accounts(&self) -> Vec<Option<String>> {
+        let now = Instant::now();
+        let watches = self.watches.lock().unwrap();
+        let mut accounts = vec![];
+        for watch in watches.values().filter(|w| w.next_poll <= now) {
+            if !accounts.contains(&watch.account) {
+                accounts.push(watch.account.clone());
+            }
+        }
+        accounts
+    }
+
+    /// Sleeps until the next device is due, or one interval if nothing is
+    /// watched yet. Returns early when `watch` or `unwatch` is called, so the
+    /// caller can recompute what is due.
+    pub async fn wait_due(&self) {
+        let due = self
+            .next_due()
+            .unwrap_or_else(|| Instant::now() + self.interval);
+        tokio::select! {
+            _ = tokio::time::sleep_until(due) => {}
+            _ = self.changed.notified() => {}
+        }
+    }
+
+    /// Polls every device of `account` that is due with `protocol` and returns
+    /// what changed since the previous poll.
+    pub async fn poll_due(
+        &self,
+        account: Option<&str>,
+        protocol: &MiCloudProtocol,
+        country: Option<&str>,
+    ) -> Vec<PollEvent> {
+        let mut events = vec![];
+        for (did, properties) in self.due(account) {
+            let result = fetch(protocol, &did, &properties, country).await;
+            events.extend(self.record(account, &did, &properties, result));
+        }
+        events
+    }
+
+    /// Reports the due devices of an account that is not logged in (anymore)
+    /// as offline, so they back off like failed polls.
+    pub fn skip_due(&self, account: Option<&str>) -> Vec<PollEvent> {
+        let mut events = vec![];
+        for (did, properties) in self.due(account) {
+            let error = match account {
+                Some(user_id) => Error::UnknownAccount(user_id.to_string()),
+                None => Error::NotLoggedIn,
+            };
+            events.extend(self.record(account, &did, &properties, Err(error)));
+        }
+        events
+    }
+
+    fn due(&self, account: Option<&str>) -> Vec<(String, Vec<PropertyId>)> {
+        let now = Instant::now();
+        let watches = self.watches.lock().unwrap();
+        watches
+            .iter()
+            .filter(|(_, w)| w.next_poll <= now && w.account.as_deref() == account)
+            .map(|(did, w)| (did.clone(), w.properties.clone()))
+            .collect()
+    }
+
+    fn record(
+        &self,
+        account: Option<&str>,
+        did: &str,
+        properties: &[PropertyId],
+        result: Result<Vec<(PropertyId, Value)>>,
+    ) -> Vec<PollEvent> {
+        let mut events = vec![];
+        let mut watches = self.watches.lock().unwrap();
+        // Unwatched or re-watched while the request was in flight
+        let Some(watch) = watches
+            .get_mut(did)
+            .filter(|w| w.properties == properties && w.account.as_deref() == account)
+        else {
+            return events;
+        };
+        match result {
+            Ok(values) => {
+                if watch.failures > 0 {
+                    events.push(PollEvent::Online {
+                        did: did.to_string(),
+                    });
+                }
+                watch.failures = 0;
+                watch.next_poll = Instant::now() + self.interval;
+                for (property, new) in values {
+                    let old = watch.values.insert(property.clone(), new.clone());
+                    if old.as_ref() != Some(&new) {
+                        events.push(PollEvent::Changed(PropertyChange {
+                            did: did.to_string(),
+                            property,
+                            old,
+                            new,
+                        }));
+                    }
+                }
+            }
+            Err(error) => {
+                watch.failures += 1;
+                let delay = self.backoff(watch.failures);
+                watch.next_poll = Instant::now() + delay;
+                events.push(PollEvent::Offline {
+                    did: did.to_string(),
+                    error,
+                    retry_in_secs: delay.as_secs(),
+                });
+            }
+        }
+        events
+    }
+
+    fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.min(16));
+        self.interval.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Reads `properties` with one `get_prop` call for the miIO names and one
+/// `get_properties` call for the MIoT ones.
+async fn fetch(
+    protocol: &MiCloudProtocol,
+    did: &str,
+    properties: &[PropertyId],
+    country: Option<&str>,
+) -> Result<Vec<(PropertyId, Value)>> {
+    let mut values = vec![];
+    let names: Vec<&str> = properties
+        .iter()
+        .filter_map(|p| match p {
+            PropertyId::Miio(name) => Some(name.as_str()),
+            PropertyId::Miot { .. } => None,
+        })
+        .collect();
+    if !names.is_empty() {
+        let res = protocol
+            .call_device(did, "get_prop", Some(json!(names)), country)
+            .await?;
+        let list = res.as_array().cloned().unwrap_or_default();
+        values.extend(
+            names
+                .iter()
+                .zip(list)
+                .map(|(name, value)| (PropertyId::Miio(name.to_string()), value)),
+        );
+    }
+
+    let requests: Vec<MiotPropertyRequest> = properties
+        .iter()
+        .filter_map(|p| match p {
+            PropertyId::Miot { siid, piid } => Some(MiotPropertyRequest {
+                did: did.to_string(),
+                siid: *siid,
+                piid: *piid,
+            }),
+            PropertyId::Miio(_) => None,
+        })
+        .collect();
+    if !requests.is_empty() {
+        let results = protocol.get_properties(&requests, country).await?;
+        // Offline devices answer every property with an error code
+        if names.is_empty() && results.iter().all(|r| r.code != 0) {
+            let code = results.first().map_or(-1, |r| r.code);
+            return Err(Error::CloudError {
+                code,
+                message: format!("Device {} did not return any property", did),
+            });
+        }
+        values.extend(results.into_iter().filter(|r| r.code == 0).filter_map(|r| {
+            let property = PropertyId::Miot {
+                siid: r.siid,
+                piid: r.piid,
+            };
+            r.value.map(|value| (property, value))
+        }));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{logged_in, mock_protocol};
+    use crate::AccountRegistry;
+    use miio_mock::{MockAccount, MockCloud};
+
+    fn changes(events: &[PollEvent]) -> Vec<(&str, Option<&Value>, &Value)> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                PollEvent::Changed(c) => Some(match &c.property {
+                    PropertyId::Miio(name) => (name.as_str(), c.old.as_ref(), &c.new),
+                    PropertyId::Miot { .. } => ("miot", c.old.as_ref(), &c.new),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn property_id_json() {
+        let ids: Vec<PropertyId> =
+            serde_json::from_value(json!(["power", { "siid": 2, "piid": 1 }])).unwrap();
+        assert_eq!(
+            ids,
+            [
+                PropertyId::Miio("power".to_string()),
+                PropertyId::Miot { siid: 2, piid: 1 }
+            ]
+        );
+        let poller = Poller::new(Duration::from_secs(5)).with_max_backoff(Duration::from_secs(60));
+        let delays: Vec<_> = (1..=5).map(|n| poller.backoff(n).as_secs()).collect();
+        assert_eq!(delays, [10, 20, 40, 60, 60]);
+    }
+
+    #[tokio::test]
+    async fn poll_changes_and_back_off() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        let rpc = |result: Value| json!({ "code": 0, "message": "ok", "result": result });
+        mock.state()
+            .responses
+            .insert("/home/rpc/1".to_string(), rpc(json!(["on", 50])));
+        let mi = logged_in(&mock).await;
+
+        let poller = Poller::new(Duration::ZERO);
+        let props = vec![
+            PropertyId::Miio("power".to_string()),
+            PropertyId::Miio("bright".to_string()),
+        ];
+        poller.watch("1", None, props);
+        let events = poller.poll_due(None, &mi, None).await;
+        assert_eq!(
+            changes(&events),
+            [("power", None, &json!("on")), ("bright", None, &json!(50))]
+        );
+
+        mock.state()
+            .responses
+            .insert("/home/rpc/1".to_string(), rpc(json!(["on", 80])));
+        let events = poller.poll_due(None, &mi, None).await;
+        assert_eq!(changes(&events), [("bright", Some(&json!(50)), &json!(80))]);
+        assert!(poller.poll_due(None, &mi, None).await.is_empty());
+
+        mock.state().responses.insert(
+            "/home/rpc/1".to_string(),
+            json!({ "code": -2, "message": "device offline" }),
+        );
+        let poller = Poller::new(Duration::from_secs(60));
+        poller.watch("1", None, vec![PropertyId::Miio("power".to_string())]);
+        let events = poller.poll_due(None, &mi, None).await;
+        assert!(matches!(
+            &events[..],
+            [PollEvent::Offline {
+                retry_in_secs: 120,
+                error: Error::CloudError { code: -2, .. },
+                ..
+            }]
+        ));
+        // Not due again until the backoff passed
+        assert!(poller.poll_due(None, &mi, None).await.is_empty());
+        assert!(poller.next_due().unwrap() > Instant::now() + Duration::from_secs(100));
+    }
+
+    #[tokio::test]
+    async fn poll_per_account() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        mock.state().props.insert(
+            "1".to_string(),
+            HashMap::from([("power".to_string(), json!("on"))]),
+        );
+        let mi = logged_in(&mock).await;
+        let user_id = mi.user_id().unwrap();
+
+        let poller = Poller::new(Duration::from_secs(60));
+        let power = || vec![PropertyId::Miio("power".to_string())];
+        poller.watch("1", Some(&user_id), power());
+        poller.watch("2", Some("removed"), power());
+        let mut accounts = poller.due_accounts();
+        accounts.sort();
+        assert_eq!(
+            accounts,
+            [Some(user_id.clone()), Some("removed".to_string())]
+        );
+
+        let events = poller.poll_due(Some(&user_id), &mi, None).await;
+        assert_eq!(changes(&events), [("power", None, &json!("on"))]);
+        let state = mock.state();
+        let rpcs: Vec<_> = state
+            .paths()
+            .into_iter()
+            .filter(|p| p.contains("/rpc/"))
+            .collect();
+        assert_eq!(rpcs, ["/app/home/rpc/1"]);
+        drop(state);
+
+        let events = poller.skip_due(Some("removed"));
+        assert!(matches!(
+            &events[..],
+            [PollEvent::Offline { did, error: Error::UnknownAccount(_), .. }] if did == "2"
+        ));
+        assert!(poller.due_accounts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn relogin_on_clone_reaches_registry() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        let mut accounts = AccountRegistry::new(mock_protocol(&mock));
+        let mut mi = accounts.new_protocol();
+        mi.login("user@example.com", "password").await.unwrap();
+        accounts.insert(mi).unwrap();
+
+        let poller = Poller::new(Duration::ZERO);
+        poller.watch("1", None, vec![PropertyId::Miio("power".to_string())]);
+        mock.state().account.service_token = "renewed-token".to_string();
+        // Polled on a clone, as the app does to not hold the registry lock
+        let clone = accounts.get(None).unwrap().clone();
+        poller.poll_due(None, &clone, None).await;
+        let session = accounts.get(None).unwrap().get_session().unwrap();
+        assert_eq!(session.service_token, "renewed-token");
+
+        // The next pass does not log in again
+        let clone = accounts.get(None).unwrap().clone();
+        poller.poll_due(None, &clone, None).await;
+        let logins = mock
+            .state()
+            .paths()
+            .iter()
+            .filter(|p| **p == "/pass/serviceLoginAuth2")
+            .count();
+        assert_eq!(logins, 2);
+    }
+
+    #[tokio::test]
+    async fn watch_wakes_wait_due() {
+        let poller = std::sync::Arc::new(Poller::new(Duration::from_secs(60)));
+        poller.watch("1", None, vec![PropertyId::Miio("power".to_string())]);
+        // Push the only watch far out, as after a failed poll
+        poller
+            .watches
+            .lock()
+            .unwrap()
+            .get_mut("1")
+            .unwrap()
+            .next_poll = Instant::now() + Poller::DEFAULT_MAX_BACKOFF;
+        // Consume the permit stored by `watch`
+        poller.wait_due().await;
+
+        let waiting = tokio::spawn({
+            let poller = poller.clone();
+            async move { poller.wait_due().await }
+        });
+        tokio::task::yield_now().await;
+        poller.watch("2", None, vec![PropertyId::Miio("power".to_string())]);
+        tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .expect("wait_due did not return after watch")
+            .unwrap();
+    }
+}
@@ -0,0 +1,523 @@
+//! Records HTTP traffic of `MiCloudProtocol` to a JSONL file and replays it.
+//!
+//! Every request, including the login flow, goes through `MiCloudProtocol::send`.
+//! With a `TrafficRecorder` each exchange is written as one JSON line with
+//! secrets redacted. With a `Replay` the recorded responses are served back in
+//! order instead of going to the network, so a recording attached to a bug
+//! report runs through the same parsing code offline.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use reqwest::{header::HeaderMap, RequestBuilder, Response, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Error, MiCloudProtocol, MiCloudSession, Result};
+
+/// Replaces secrets. Valid base64, so a replayed `ssecurity` still signs requests.
+const REDACTED: &str = "REDACTED";
+
+/// JSON keys, form fields, query params and cookies whose values are redacted,
+/// secrets as well as personal data such as the email (`user`) and addresses
+const SECRET_KEYS: &[&str] = &[
+    "hash",
+    "password",
+    "ssecurity",
+    "psecurity",
+    "passToken",
+    "serviceToken",
+    "yetAnotherServiceToken",
+    "token",
+    "beaconkey",
+    "ble_key",
+    "user",
+    "signature",
+    "_nonce",
+    "nonce",
+    "rc4_hash__",
+    "clientSign",
+    "_ssign",
+    "captCode",
+    "ticket",
+    "context",
+    "userId",
+    "cUserId",
+    "ssid",
+    "bssid",
+    "mac",
+    "localip",
+    "latitude",
+    "longitude",
+];
+
+/// Headers redacted as a whole
+const SECRET_HEADERS: &[&str] = &["authorization", "extension-pragma"];
+
+/// One request and its response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Exchange {
+    /// Unix time in milliseconds
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub method: String,
+    pub url: String,
+    pub request_headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub form: Vec<(String, String)>,
+    /// Params of an API request before they are signed or encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    /// 0 when no response arrived
+    pub status: u16,
+    #[serde(default)]
+    pub response_headers: Vec<(String, String)>,
+    /// Parsed JSON, decrypted for RC4 requests, text for anything else
+    #[serde(default)]
+    pub body: Value,
+    /// Network error in place of a response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Exchange {
+    fn redact(&mut self) {
+        self.url = redact_url(&self.url);
+        for (name, value) in self
+            .request_headers
+            .iter_mut()
+            .chain(self.response_headers.iter_mut())
+        {
+            *value = redact_header(name, value);
+        }
+        for (name, value) in self.form.iter_mut() {
+            if is_secret(name) {
+                *value = REDACTED.to_string();
+            }
+        }
+        if let Some(data) = self.data.as_mut() {
+            redact_json(data);
+        }
+        redact_json(&mut self.body);
+    }
+
+    fn path(&self) -> String {
+        Url::parse(&self.url).map_or_else(|_| self.url.clone(), |url| url.path().to_string())
+    }
+
+    fn into_response(self) -> Result<Response> {
+        let mut response = http::Response::builder().status(self.status);
+        for (name, value) in &self.response_headers {
+            // The body is replayed decoded and may differ in length
+            if !["content-length", "content-encoding", "transfer-encoding"].contains(&name.as_str())
+            {
+                response = response.header(name, value);
+            }
+        }
+        let body = match self.body {
+            Value::String(text) => text,
+            body => body.to_string(),
+        };
+        Ok(response.body(body).map_err(Error::decode)?.into())
+    }
+}
+
+fn is_secret(key: &str) -> bool {
+    SECRET_KEYS.contains(&key)
+}
+
+fn redact_url(url: &str) -> String {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    if parsed.query().is_none() {
+        return url.to_string();
+    }
+    let pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .map(|(k, v)| {
+            let v = if is_secret(&k) {
+                REDACTED.to_string()
+            } else {
+                v.into_owned()
+            };
+            (k.into_owned(), v)
+        })
+        .collect();
+    parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    parsed.to_string()
+}
+
+fn redact_header(name: &str, value: &str) -> String {
+    match name {
+        "cookie" => value
+            .split("; ")
+            .map(|cookie| match cookie.split_once('=') {
+                Some((name, _)) => format!("{}={}", name, REDACTED),
+                None => cookie.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("; "),
+        // Only the first pair is the cookie, the rest are attributes
+        "set-cookie" => match value.split_once('=') {
+            Some((name, rest)) => {
+                let attributes = rest.find(';').map_or("", |i| &rest[i..]);
+                format!("{}={}{}", name, REDACTED, attributes)
+            }
+            None => value.to_string(),
+        },
+        "location" => redact_url(value),
+        name if SECRET_HEADERS.contains(&name) => REDACTED.to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Keeps the JSON type of numbers so replayed responses still parse.
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if !is_secret(key) {
+                    redact_json(value);
+                } else if value.is_number() {
+                    *value = Value::from(0);
+                } else if !value.is_null() {
+                    *value = Value::from(REDACTED);
+                }
+            }
+        }
+        Value::Array(list) => list.iter_mut().for_each(redact_json),
+        Value::String(text) if text.starts_with("http") => *text = redact_url(text),
+        _ => {}
+    }
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.as_str().to_string(), value)
+        })
+        .collect()
+}
+
+fn form_pairs(body: &[u8]) -> Vec<(String, String)> {
+    let decode = |s: &str| {
+        let s = s.replace('+', " ");
+        urlencoding::decode(&s).map_or(s.clone(), |d| d.into_owned())
+    };
+    String::from_utf8_lossy(body)
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(name), decode(value))
+        })
+        .collect()
+}
+
+/// Writes every exchange as one redacted JSON line.
+pub struct TrafficRecorder {
+    file: Mutex<File>,
+}
+
+impl TrafficRecorder {
+    /// Appends to `path`, creating it if needed.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(TrafficRecorder {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, mut exchange: Exchange) -> Result<()> {
+        exchange.redact();
+        let mut line = serde_json::to_string(&exchange)?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Recorded exchanges served back in order.
+///
+/// A request gets the first unused exchange with the same method and URL
+/// path, so host, country and query may differ from the recording. Logins
+/// that need 2FA are not replayed, their cookies never reach the client.
+pub struct Replay {
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl Replay {
+    pub fn new(exchanges: Vec<Exchange>) -> Self {
+        Replay {
+            exchanges: Mutex::new(exchanges.into()),
+        }
+    }
+
+    /// Reads a file written by `TrafficRecorder`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let mut exchanges = vec![];
+        for line in file.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                exchanges.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(Self::new(exchanges))
+    }
+
+    /// Exchanges that have not been served yet.
+    pub fn remaining(&self) -> usize {
+        self.exchanges.lock().unwrap().len()
+    }
+
+    fn next(&self, method: &str, url: &Url) -> Result<Exchange> {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let position = exchanges
+            .iter()
+            .position(|e| e.method == method && e.path() == url.path());
+        let exchange = position.and_then(|i| exchanges.remove(i));
+        exchange.ok_or_else(|| {
+            Error::Network(format!(
+                "No recorded response for {} {}",
+                method,
+                url.path()
+            ))
+        })
+    }
+}
+
+/// Extra detail recorded for Mi Cloud API requests.
+pub(crate) struct ApiCall<'a> {
+    pub data: &'a Value,
+    /// Set for RC4 requests, so the body is recorded decrypted
+    pub signed_nonce: Option<&'a str>,
+}
+
+impl MiCloudProtocol {
+    /// Records every following request to `recorder`, shared with clones.
+    pub fn set_traffic_recorder(&mut self, recorder: Option<TrafficRecorder>) {
+        self.recorder = recorder.map(Arc::new);
+    }
+
+    /// An instance that answers every request from `replay` and never goes to
+    /// the network. It is logged in with a placeholder session, calling
+    /// `login` replays the recorded login instead.
+    pub fn replaying(replay: Replay) -> Self {
+        let mut protocol = Self::from_session(MiCloudSession {
+            user_id: "0".to_string(),
+            ssecurity: REDACTED.to_string(),
+            service_token: REDACTED.to_string(),
+            client_id: "android_replay".to_string(),
+            country: "cn".to_string(),
+            username: None,
+        });
+        protocol.replay = Some(Arc::new(replay));
+        protocol
+    }
+
+    pub(crate) async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        self.send_api(request, None).await
+    }
+
+    pub(crate) async fn send_api(
+        &self,
+        request: RequestBuilder,
+        api: Option<ApiCall<'_>>,
+    ) -> Result<Response> {
+        if self.recorder.is_none() && self.replay.is_none() {
+            return Ok(request.send().await?);
+        }
+        let (client, request) = request.build_split();
+        let request = request?;
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let clock = Instant::now();
+        let mut exchange = Exchange {
+            started_at,
+            duration_ms: 0,
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            request_headers: header_pairs(request.headers()),
+            form: request
+                .body()
+                .and_then(|b| b.as_bytes())
+                .map(form_pairs)
+                .unwrap_or_default(),
+            data: api.as_ref().map(|api| api.data.clone()),
+            status: 0,
+            response_headers: vec![],
+            body: Value::Null,
+            error: None,
+        };
+
+        let response = match &self.replay {
+            Some(replay) => replay
+                .next(&exchange.method, request.url())?
+                .into_response(),
+            None => client.execute(request).await.map_err(Error::from),
+        };
+        let Some(recorder) = &self.recorder else {
+            return response;
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                exchange.duration_ms = clock.elapsed().as_millis() as u64;
+                exchange.error = Some(e.to_string());
+                self.record(recorder, exchange);
+                return Err(e);
+            }
+        };
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = response.bytes().await?;
+        exchange.duration_ms = clock.elapsed().as_millis() as u64;
+        exchange.status = status.as_u16();
+        exchange.response_headers = header_pairs(&headers);
+        exchange.body = self.decode_body(&bytes, api.and_then(|api| api.signed_nonce));
+        self.record(recorder, exchange);
+
+        let mut rebuilt = http::Response::builder().status(status);
+        if let Some(map) = rebuilt.headers_mut() {
+            *map = headers;
+        }
+        Ok(rebuilt.body(bytes).map_err(Error::decode)?.into())
+    }
+
+    fn record(&self, recorder: &TrafficRecorder, exchange: Exchange) {
+        // A recording must never fail the request itself
+        if let Err(e) = recorder.record(exchange) {
+            debug!("[miio::recorder] failed to record exchange: {}", e);
+        }
+    }
+
+    fn decode_body(&self, bytes: &[u8], signed_nonce: Option<&str>) -> Value {
+        let Ok(text) = std::str::from_utf8(bytes) else {
+            return Value::from(format!("<{} bytes>", bytes.len()));
+        };
+        if let Ok(json) = crate::parse_response_json(text) {
+            return json;
+        }
+        let decrypted = signed_nonce
+            .and_then(|nonce| self.decrypt_rc4(nonce, text).ok())
+            .and_then(|plain| serde_json::from_str(&plain).ok());
+        decrypted.unwrap_or_else(|| Value::from(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock_protocol;
+    use miio_mock::{MockAccount, MockCloud};
+    use serde_json::json;
+
+    #[test]
+    fn redact_exchange() {
+        let mut exchange = Exchange {
+            started_at: 0,
+            duration_ms: 0,
+            method: "POST".to_string(),
+            url: "https://account.xiaomi.com/sts?nonce=1&clientSign=abc&d=ok".to_string(),
+            request_headers: vec![(
+                "cookie".to_string(),
+                "userId=1; serviceToken=secret".to_string(),
+            )],
+            form: vec![
+                ("hash".to_string(), "ABCDEF".to_string()),
+                ("user".to_string(), "user@example.com".to_string()),
+            ],
+            data: Some(json!({ "dids": ["1"] })),
+            status: 200,
+            response_headers: vec![(
+                "set-cookie".to_string(),
+                "serviceToken=secret; Path=/; HttpOnly".to_string(),
+            )],
+            body: json!({
+                "userId": 42,
+                "ssecurity": "c2VjcmV0",
+                "location": "https://sts.api.io.mi.com/sts?_ssign=x&d=ok",
+                "result": { "list": [{ "did": "1", "token": "00ff", "ssid": null }] }
+            }),
+            error: None,
+        };
+        exchange.redact();
+        let line = serde_json::to_string(&exchange).unwrap();
+        assert!(!line.contains("secret") && !line.contains("ABCDEF") && !line.contains("00ff"));
+        assert_eq!(
+            exchange.url,
+            "https://account.xiaomi.com/sts?nonce=REDACTED&clientSign=REDACTED&d=ok"
+        );
+        assert_eq!(
+            exchange.request_headers[0].1,
+            "userId=REDACTED; serviceToken=REDACTED"
+        );
+        assert_eq!(
+            exchange.response_headers[0].1,
+            "serviceToken=REDACTED; Path=/; HttpOnly"
+        );
+        assert_eq!(exchange.form[1].1, REDACTED);
+        assert_eq!(exchange.body["userId"], 0);
+        assert_eq!(exchange.body["result"]["list"][0]["did"], "1");
+        assert_eq!(exchange.body["result"]["list"][0]["ssid"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let path = std::env::temp_dir().join(format!("miio-traffic-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mock = MockCloud::start(MockAccount::default()).await;
+        mock.state().devices = vec![json!({
+            "did": "1", "model": "m", "token": "00ff",
+            "mac": "7C:49:EB:01:02:03", "localip": "192.168.1.23"
+        })];
+        let mut mi = mock_protocol(&mock);
+        mi.set_traffic_recorder(Some(TrafficRecorder::create(&path).unwrap()));
+        mi.login("user@example.com", "password").await.unwrap();
+        let devices = mi.get_devices(None, None).await.unwrap();
+        assert_eq!(devices[0].token, "00ff");
+        let session = mi.get_session().unwrap();
+        drop(mock);
+
+        let recording = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(recording.lines().count(), 4);
+        assert!(!recording.contains(&session.service_token));
+        assert!(!recording.contains(&session.ssecurity));
+        assert!(!recording.contains("00ff"));
+        for personal in ["user@example.com", "7C:49:EB:01:02:03", "192.168.1.23"] {
+            assert!(!recording.contains(personal), "{} was recorded", personal);
+        }
+        let api: Exchange = serde_json::from_str(recording.lines().last().unwrap()).unwrap();
+        assert_eq!(
+            api.data,
+            Some(json!({ "getVirtualModel": false, "getHuamiDevices": 0 }))
+        );
+
+        let mut replayed = MiCloudProtocol::replaying(Replay::from_file(&path).unwrap());
+        replayed
+            .login("user@example.com", "password")
+            .await
+            .unwrap();
+        let devices = replayed.get_devices(None, None).await.unwrap();
+        assert_eq!(
+            (devices[0].did.as_str(), devices[0].token.as_str()),
+            ("1", REDACTED)
+        );
+        let err = replayed.get_devices(None, None).await;
+        assert!(matches!(err, Err(Error::Network(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,443 @@
+//! Scenes (run by hand) and automations (run by a trigger) of the Mi Home app.
+//!
+//! Older scenes live behind `/scene/*`, scenes created by recent app versions
+//! behind the MIoT `AppSceneService`. `get_scenes` merges both.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{cloud_error, lenient, Error, MiCloudProtocol, Result, Transport};
+
+const APP_SCENE_SERVICE: &str = "/appgateway/miot/appsceneservice/AppSceneService";
+/// Trigger key of scenes that are started from the app
+const MANUAL_TRIGGER: &str = "user.click";
+
+/// Which API a scene was listed from, and has to be run or changed through.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SceneSource {
+    /// `/scene/list`
+    Legacy,
+    /// `AppSceneService/GetSceneList`
+    Miot,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SceneKind {
+    /// Started by hand from the app, see `MiCloudProtocol::run_scene`
+    Manual,
+    /// Started by its triggers while enabled
+    Automation,
+}
+
+/// Something that starts an automation, e.g. a device event or a time.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SceneTrigger {
+    /// e.g. `event.lumi.sensor_magnet.v2.open` or `user.click`
+    #[serde(deserialize_with = "lenient::string")]
+    pub key: String,
+    /// Device raising the trigger, empty for timers and the app
+    #[serde(deserialize_with = "lenient::string")]
+    pub did: String,
+    #[serde(deserialize_with = "lenient::string")]
+    pub name: String,
+    /// Condition value, as the cloud sent it
+    pub value: Value,
+}
+
+/// Something a scene does, usually a device command.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SceneAction {
+    /// Target device, empty for actions such as notifications or delays
+    #[serde(deserialize_with = "lenient::string")]
+    pub did: String,
+    #[serde(deserialize_with = "lenient::string")]
+    pub name: String,
+    /// e.g. `set_power` or a MIoT `siid.piid` key
+    #[serde(deserialize_with = "lenient::string")]
+    pub command: String,
+    pub value: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Scene {
+    pub id: String,
+    pub name: String,
+    /// Matches `Home::id`, empty for legacy scenes listed without a home
+    pub home_id: String,
+    pub kind: SceneKind,
+    pub enabled: bool,
+    pub triggers: Vec<SceneTrigger>,
+    pub actions: Vec<SceneAction>,
+    pub source: SceneSource,
+}
+
+impl Scene {
+    /// Whether `did` triggers the scene or is controlled by it.
+    pub fn references(&self, did: &str) -> bool {
+        self.triggers.iter().any(|t| t.did == did) || self.actions.iter().any(|a| a.did == did)
+    }
+}
+
+fn kind_of(triggers: &[SceneTrigger]) -> SceneKind {
+    if triggers.iter().all(|t| t.key == MANUAL_TRIGGER) {
+        SceneKind::Manual
+    } else {
+        SceneKind::Automation
+    }
+}
+
+// `/scene/list` entry: the triggers are in `setting.launch.attr`, the
+// actions in `setting.action_list` with the command in `payload`
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LegacyScene {
+    #[serde(deserialize_with = "lenient::string")]
+    us_id: String,
+    #[serde(deserialize_with = "lenient::string")]
+    name: String,
+    #[serde(deserialize_with = "lenient::string")]
+    home_id: String,
+    setting: LegacySetting,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LegacySetting {
+    #[serde(deserialize_with = "lenient::flag")]
+    enable: bool,
+    launch: LegacyLaunch,
+    action_list: Vec<LegacyAction>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LegacyLaunch {
+    attr: Vec<SceneTrigger>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LegacyAction {
+    #[serde(deserialize_with = "lenient::string")]
+    name: String,
+    payload: SceneAction,
+}
+
+impl From<LegacyScene> for Scene {
+    fn from(scene: LegacyScene) -> Self {
+        let triggers = scene.setting.launch.attr;
+        Scene {
+            id: scene.us_id,
+            name: scene.name,
+            home_id: scene.home_id,
+            kind: kind_of(&triggers),
+            enabled: scene.setting.enable,
+            triggers,
+            actions: scene
+                .setting
+                .action_list
+                .into_iter()
+                .map(|a| SceneAction {
+                    name: if a.payload.name.is_empty() {
+                        a.name
+                    } else {
+                        a.payload.name
+                    },
+                    ..a.payload
+                })
+                .collect(),
+            source: SceneSource::Legacy,
+        }
+    }
+}
+
+// `GetSceneList` entry
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct MiotScene {
+    #[serde(deserialize_with = "lenient::string")]
+    scene_id: String,
+    #[serde(deserialize_with = "lenient::string")]
+    name: String,
+    #[serde(deserialize_with = "lenient::string")]
+    home_id: String,
+    #[serde(deserialize_with = "lenient::flag")]
+    enable: bool,
+    trigger: MiotTrigger,
+    #[serde(alias = "action_list")]
+    actions: Vec<SceneAction>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct MiotTrigger {
+    triggers: Vec<SceneTrigger>,
+}
+
+impl From<MiotScene> for Scene {
+    fn from(scene: MiotScene) -> Self {
+        let triggers = scene.trigger.triggers;
+        Scene {
+            id: scene.scene_id,
+            name: scene.name,
+            home_id: scene.home_id,
+            kind: kind_of(&triggers),
+            enabled: scene.enable,
+            triggers,
+            actions: scene.actions,
+            source: SceneSource::Miot,
+        }
+    }
+}
+
+fn parse_legacy_scenes(res: &Value) -> Result<Vec<Scene>> {
+    // A list, or a map from scene id to scene
+    let list: Vec<Value> = match &res["result"] {
+        Value::Array(list) => list.clone(),
+        Value::Object(map) => map.values().cloned().collect(),
+        _ => return Err(cloud_error(res, "Get scenes failed")),
+    };
+    let scenes: Vec<LegacyScene> = serde_json::from_value(Value::Array(list))?;
+    Ok(scenes.into_iter().map(Scene::from).collect())
+}
+
+fn parse_miot_scenes(res: &Value) -> Result<Vec<Scene>> {
+    if res["result"].is_null() {
+        return Err(cloud_error(res, "Get scenes failed"));
+    }
+    let scenes: Vec<MiotScene> = match res["result"]["scene_info_list"].as_array() {
+        Some(list) => serde_json::from_value(Value::Array(list.clone()))?,
+        None => vec![],
+    };
+    Ok(scenes.into_iter().map(Scene::from).collect())
+}
+
+impl MiCloudProtocol {
+    /// Lists scenes and automations of `home_id`, or of every home.
+    pub async fn get_scenes(
+        &self,
+        home_id: Option<&str>,
+        country: Option<&str>,
+    ) -> Result<Vec<Scene>> {
+        let country = country.unwrap_or(self.country.as_str());
+        let homes: Vec<(String, u64)> = match home_id {
+            Some(id) => vec![(id.to_string(), 0)],
+            None => self
+                .get_homes(Some(country))
+                .await?
+                .into_iter()
+                .map(|home| (home.id, home.uid))
+                .collect(),
+        };
+
+        let mut scenes = vec![];
+        for (home_id, owner) in homes {
+            let req = json!({ "home_id": home_id, "api_version": 5 });
+            let res = self.request("/scene/list", req, country).await?;
+            for mut scene in parse_legacy_scenes(&res)? {
+                if scene.home_id.is_empty() {
+                    scene.home_id = home_id.clone();
+                }
+                scenes.push(scene);
+            }
+
+            let mut req = json!({ "home_id": home_id });
+            if owner != 0 {
+                req["owner_uid"] = json!(owner);
+            }
+            let path = format!("{}/GetSceneList", APP_SCENE_SERVICE);
+            let res = self
+                .request_with(Transport::Rc4, &path, req, country)
+                .await?;
+            for mut scene in parse_miot_scenes(&res)? {
+                if scene.home_id.is_empty() {
+                    scene.home_id = home_id.clone();
+                }
+                if !scenes.iter().any(|s: &Scene| s.id == scene.id) {
+                    scenes.push(scene);
+                }
+            }
+        }
+        Ok(scenes)
+    }
+
+    /// Runs a manual scene now. Automations are rejected without a request,
+    /// the cloud would only start them through their triggers.
+    pub async fn run_scene(&self, scene: &Scene, country: Option<&str>) -> Result<()> {
+        if scene.kind != SceneKind::Manual {
+            return Err(Error::Decode(format!(
+                "Scene {} is an automation, only manual scenes can be run",
+                scene.name
+            )));
+        }
+        let country = country.unwrap_or(self.country.as_str());
+        let res = match scene.source {
+            SceneSource::Legacy => {
+                let req = json!({ "us_id": scene.id, "key": MANUAL_TRIGGER });
+                self.request("/scene/start", req, country).await?
+            }
+            SceneSource::Miot => {
+                let req = json!({
+                    "scene_id": scene.id,
+                    "trigger_key": MANUAL_TRIGGER,
+                    "home_id": scene.home_id,
+                });
+                let path = format!("{}/RunScene", APP_SCENE_SERVICE);
+                self.request_with(Transport::Rc4, &path, req, country)
+                    .await?
+            }
+        };
+        check_ok(&res, "Run scene failed")
+    }
+
+    /// Turns an automation on or off.
+    pub async fn set_scene_enabled(
+        &self,
+        scene: &Scene,
+        enabled: bool,
+        country: Option<&str>,
+    ) -> Result<()> {
+        let country = country.unwrap_or(self.country.as_str());
+        let res = match scene.source {
+            SceneSource::Legacy => {
+                let req = json!({ "us_id": scene.id, "enable": enabled as u8 });
+                self.request("/scene/enable", req, country).await?
+            }
+            SceneSource::Miot => {
+                let req = json!({
+                    "scene_id": scene.id,
+                    "enable": enabled,
+                    "home_id": scene.home_id,
+                });
+                let path = format!("{}/UpdateSceneEnable", APP_SCENE_SERVICE);
+                self.request_with(Transport::Rc4, &path, req, country)
+                    .await?
+            }
+        };
+        check_ok(&res, "Change scene failed")
+    }
+}
+
+fn check_ok(res: &Value, fallback_msg: &str) -> Result<()> {
+    if res["code"].as_i64() == Some(0) {
+        Ok(())
+    } else {
+        Err(cloud_error(res, fallback_msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::logged_in;
+    use miio_mock::{MockAccount, MockCloud};
+
+    fn legacy_list() -> Value {
+        json!({ "code": 0, "message": "ok", "result": [{
+            "us_id": 1001,
+            "name": "Door opened",
+            "st_id": "15",
+            "setting": {
+                "enable": "1",
+                "launch": { "attr": [{
+                    "key": "event.lumi.sensor_magnet.v2.open",
+                    "did": "lumi.158d0001",
+                    "name": "Door sensor",
+                    "value": ""
+                }] },
+                "action_list": [{
+                    "name": "Hall lamp",
+                    "type": 0,
+                    "payload": { "did": "1", "command": "set_power", "value": "on" }
+                }]
+            }
+        }] })
+    }
+
+    fn miot_list() -> Value {
+        json!({ "code": 0, "message": "ok", "result": { "scene_info_list": [{
+            "scene_id": "2002",
+            "name": "Good night",
+            "enable": true,
+            "trigger": { "express": 0, "triggers": [{ "key": "user.click" }] },
+            "actions": [{ "did": "2", "name": "Bedroom lamp", "command": "2.1", "value": false }]
+        }] } })
+    }
+
+    #[test]
+    fn parse_scene_lists() {
+        let scenes = parse_legacy_scenes(&legacy_list()).unwrap();
+        let scene = &scenes[0];
+        assert_eq!(scene.id, "1001");
+        assert_eq!(scene.kind, SceneKind::Automation);
+        assert!(scene.enabled);
+        assert_eq!(scene.triggers[0].did, "lumi.158d0001");
+        assert_eq!(scene.actions[0].name, "Hall lamp");
+        assert_eq!(scene.actions[0].command, "set_power");
+        assert!(scene.references("lumi.158d0001") && scene.references("1"));
+        assert!(!scene.references("2"));
+
+        let scenes = parse_miot_scenes(&miot_list()).unwrap();
+        assert_eq!(scenes[0].kind, SceneKind::Manual);
+        assert_eq!(scenes[0].source, SceneSource::Miot);
+        assert_eq!(scenes[0].actions[0].value, false);
+
+        let err = parse_legacy_scenes(&json!({ "code": -1, "message": "bad home" }));
+        assert!(matches!(err, Err(Error::CloudError { code: -1, .. })));
+    }
+
+    #[tokio::test]
+    async fn list_run_and_enable() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        let ok = json!({ "code": 0, "message": "ok", "result": "" });
+        {
+            let mut state = mock.state();
+            state
+                .responses
+                .insert("/scene/list".to_string(), legacy_list());
+            state
+                .responses
+                .insert(format!("{}/GetSceneList", APP_SCENE_SERVICE), miot_list());
+            state
+                .responses
+                .insert("/scene/enable".to_string(), ok.clone());
+            state
+                .responses
+                .insert(format!("{}/RunScene", APP_SCENE_SERVICE), ok);
+        }
+        let mi = logged_in(&mock).await;
+
+        let scenes = mi.get_scenes(Some("10"), None).await.unwrap();
+        let ids: Vec<_> = scenes.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["1001", "2002"]);
+        assert!(scenes.iter().all(|s| s.home_id == "10"));
+
+        mi.run_scene(&scenes[1], None).await.unwrap();
+        mi.set_scene_enabled(&scenes[0], false, None).await.unwrap();
+        let requests = mock.state().requests.clone();
+        let data = |path: &str| {
+            requests
+                .iter()
+                .find(|r| r.path == path)
+                .and_then(|r| r.data.clone())
+                .unwrap()
+        };
+        let run = data(&format!("/app{}/RunScene", APP_SCENE_SERVICE));
+        assert_eq!(run["scene_id"], "2002");
+        assert_eq!(
+            data("/app/scene/enable"),
+            json!({ "us_id": "1001", "enable": 0 })
+        );
+
+        let err = mi.set_scene_enabled(&scenes[1], true, None).await;
+        assert!(matches!(err, Err(Error::CloudError { .. })));
+
+        let err = mi.run_scene(&scenes[0], None).await;
+        assert!(matches!(err, Err(Error::Decode(_))));
+        assert!(!mock.state().paths().contains(&"/app/scene/start"));
+    }
+}
@@ -0,0 +1,335 @@
+//! MIoT spec downloader with an on-disk cache.
+//!
+//! Specs are published at <https://miot-spec.org/miot-spec-v2>: `instances?status=all`
+//! maps device models to URNs and `instance?type={urn}` returns the spec itself.
+
+use crate::{Error, Result};
+use log::debug;
+use reqwest::Client;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiotSpec {
+    #[serde(rename = "type")]
+    pub urn: String,
+    pub description: String,
+    #[serde(default)]
+    pub services: Vec<MiotService>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiotService {
+    pub iid: u32,
+    #[serde(rename = "type")]
+    pub urn: String,
+    pub description: String,
+    #[serde(default)]
+    pub properties: Vec<MiotProperty>,
+    #[serde(default)]
+    pub actions: Vec<MiotAction>,
+    #[serde(default)]
+    pub events: Vec<MiotEvent>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiotProperty {
+    pub iid: u32,
+    #[serde(rename = "type")]
+    pub urn: String,
+    pub description: String,
+    pub format: String,
+    #[serde(default)]
+    pub access: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// `[min, max, step]`
+    #[serde(
+        rename = "value-range",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub value_range: Option<Vec<f64>>,
+    #[serde(rename = "value-list", default, skip_serializing_if = "Vec::is_empty")]
+    pub value_list: Vec<MiotValueListItem>,
+}
+
+impl MiotProperty {
+    pub fn is_readable(&self) -> bool {
+        self.access.iter().any(|a| a == "read")
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.access.iter().any(|a| a == "write")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiotValueListItem {
+    pub value: Value,
+    pub description: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiotAction {
+    pub iid: u32,
+    #[serde(rename = "type")]
+    pub urn: String,
+    pub description: String,
+    /// Property iids of the input arguments
+    #[serde(rename = "in", default)]
+    pub input: Vec<u32>,
+    #[serde(default)]
+    pub out: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MiotEvent {
+    pub iid: u32,
+    #[serde(rename = "type")]
+    pub urn: String,
+    pub description: String,
+    #[serde(default)]
+    pub arguments: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+struct SpecInstances {
+    instances: Vec<SpecInstance>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SpecInstance {
+    model: String,
+    status: String,
+    version: u32,
+    #[serde(rename = "type")]
+    urn: String,
+}
+
+const INSTANCES_CACHE_FILE: &str = "instances.json";
+
+pub struct MiotSpecClient {
+    base_url: String,
+    cache_dir: Option<PathBuf>,
+    max_age: Duration,
+    client: Client,
+}
+
+impl MiotSpecClient {
+    pub const DEFAULT_BASE_URL: &'static str = "https://miot-spec.org/miot-spec-v2";
+    pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+    pub fn new() -> Self {
+        MiotSpecClient {
+            base_url: Self::DEFAULT_BASE_URL.to_string(),
+            cache_dir: None,
+            max_age: Self::DEFAULT_MAX_AGE,
+            client: Client::new(),
+        }
+    }
+
+    /// Stores downloaded specs in `dir`, refetching entries older than `max_age`.
+    pub fn with_cache(mut self, dir: impl Into<PathBuf>, max_age: Duration) -> Self {
+        self.cache_dir = Some(dir.into());
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn _override_base_url(&mut self, base_url: &str) {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+    }
+
+    /// Resolves a device model (e.g. `yeelink.light.color1`) to the URN of
+    /// its latest released spec.
+    pub async fn resolve_urn(&self, model: &str) -> Result<String> {
+        let instances: Vec<SpecInstance> = self
+            .cached(INSTANCES_CACHE_FILE, || async {
+                let url = format!("{}/instances?status=all", self.base_url);
+                let res: SpecInstances = self.fetch(&url).await?;
+                Ok(res.instances)
+            })
+            .await?;
+        instances
+            .into_iter()
+            .filter(|i| i.model == model && i.status == "released")
+            .max_by_key(|i| i.version)
+            .map(|i| i.urn)
+            .ok_or_else(|| Error::UnknownModel(model.to_string()))
+    }
+
+    pub async fn get_spec(&self, model: &str) -> Result<MiotSpec> {
+        let urn = self.resolve_urn(model).await?;
+        self.get_spec_by_urn(&urn).await
+    }
+
+    pub async fn get_spec_by_urn(&self, urn: &str) -> Result<MiotSpec> {
+        let file_name = format!("{}.json", urn.replace([':', '/'], "_"));
+        self.cached(&file_name, || async {
+            let url = format!(
+                "{}/instance?type={}",
+                self.base_url,
+                urlencoding::encode(urn)
+            );
+            self.fetch(&url).await
+        })
+        .await
+    }
+
+    async fn fetch<T: DeserializeOwned>(&self, url: &str) -> Result<T> {
+        debug!("[miio::spec] fetch {}", url);
+        let res = self.client.get(url).send().await?;
+        if !res.status().is_success() {
+            return Err(Error::Http(res.status().as_u16()));
+        }
+        Ok(res.json().await?)
+    }
+
+    /// Returns a fresh cache entry, or downloads and stores it. A stale entry
+    /// is still used when the download fails.
+    async fn cached<T, F, Fut>(&self, file_name: &str, download: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<T>>,
+    {
+        let Some(dir) = &self.cache_dir else {
+            return download().await;
+        };
+        let path = dir.join(file_name);
+        let cached = read_cache::<T>(&path);
+        if let Some((value, age)) = cached {
+            if age <= self.max_age {
+                return Ok(value);
+            }
+            return match download().await {
+                Ok(fresh) => {
+                    write_cache(&path, &fresh);
+                    Ok(fresh)
+                }
+                Err(e) => {
+                    debug!("[miio::spec] using stale {}: {}", path.display(), e);
+                    Ok(value)
+                }
+            };
+        }
+        let fresh = download().await?;
+        write_cache(&path, &fresh);
+        Ok(fresh)
+    }
+}
+
+impl Default for MiotSpecClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn read_cache<T: DeserializeOwned>(path: &Path) -> Option<(T, Duration)> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default();
+    let value = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    Some((value, age))
+}
+
+fn write_cache<T: Serialize>(path: &Path, value: &T) {
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(path, serde_json::to_string(value)?));
+    if let Err(e) = result {
+        debug!("[miio::spec] failed to write {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const INSTANCES: &str = include_str!("../fixtures/miot-spec/instances.json");
+    const SPEC: &str = include_str!("../fixtures/miot-spec/yeelink-color1-2.json");
+
+    /// Serves the recorded spec files, counting requests.
+    async fn serve_fixtures() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut buf = vec![0u8; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]);
+                let path = request.split_whitespace().nth(1).unwrap_or("");
+                let (status, body) = if path == "/instances?status=all" {
+                    ("200 OK", INSTANCES)
+                } else if path.ends_with("yeelink-color1%3A2") {
+                    ("200 OK", SPEC)
+                } else {
+                    ("404 Not Found", "{}")
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (base_url, hits)
+    }
+
+    #[test]
+    fn parse_spec() {
+        let spec: MiotSpec = serde_json::from_str(SPEC).unwrap();
+        let light = &spec.services[1];
+        assert_eq!(light.description, "Light");
+        let brightness = &light.properties[2];
+        assert_eq!(brightness.unit.as_deref(), Some("percentage"));
+        assert_eq!(brightness.value_range, Some(vec![1.0, 100.0, 1.0]));
+        assert!(brightness.is_writable());
+        assert_eq!(light.properties[1].value_list[1].description, "Night");
+        assert_eq!(light.actions[1].input, vec![3]);
+        assert_eq!(light.events[0].description, "Power Changed");
+        assert!(!spec.services[0].properties[0].is_writable());
+    }
+
+    #[tokio::test]
+    async fn download_and_cache_spec() {
+        let (base_url, hits) = serve_fixtures().await;
+        let cache_dir = std::env::temp_dir().join(format!("miot-spec-{}", uuid::Uuid::new_v4()));
+        let mut client =
+            MiotSpecClient::new().with_cache(&cache_dir, MiotSpecClient::DEFAULT_MAX_AGE);
+        client._override_base_url(&base_url);
+
+        let spec = client.get_spec("yeelink.light.color1").await.unwrap();
+        assert_eq!(
+            spec.urn,
+            "urn:miot-spec-v2:device:light:0000A001:yeelink-color1:2"
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        let cached = client.get_spec("yeelink.light.color1").await.unwrap();
+        assert_eq!(cached, spec);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        assert!(client.get_spec("unknown.model").await.is_err());
+        fs::remove_dir_all(cache_dir).unwrap();
+    }
+}
@@ -0,0 +1,40 @@
+//! Fixtures shared by the unit tests.
+
+use miio_mock::MockCloud;
+use serde_json::{json, Value};
+
+use crate::{Device, MiCloudProtocol, UrlsConfig};
+
+/// A device as the cloud lists it, with `fields` replacing the defaults.
+pub(crate) fn device_json(did: &str, fields: Value) -> Value {
+    let mut device = json!({
+        "adminFlag": 1, "bssid": "", "desc": "", "did": did, "extra": {},
+        "family_id": 0, "isOnline": true, "latitude": "0", "localip": "192.168.1.20",
+        "longitude": "0", "mac": "", "model": "yeelink.light.color1", "name": did,
+        "p2p_id": "", "parent_id": "", "parent_model": "", "password": "",
+        "pd_id": 0, "permitLevel": 16, "pid": "0", "reset_flag": 0, "rssi": -50,
+        "shareFlag": 0, "show_mode": 1, "ssid": "", "token": "", "uid": 1
+    });
+    if let (Some(device), Value::Object(fields)) = (device.as_object_mut(), fields) {
+        device.extend(fields);
+    }
+    device
+}
+
+pub(crate) fn device(did: &str, fields: Value) -> Device {
+    serde_json::from_value(device_json(did, fields)).unwrap()
+}
+
+/// A protocol talking to `mock` instead of the Xiaomi servers.
+pub(crate) fn mock_protocol(mock: &MockCloud) -> MiCloudProtocol {
+    let mut mi = MiCloudProtocol::new();
+    mi._override_urls(UrlsConfig::with_base_url(mock.base_url()));
+    mi
+}
+
+/// `mock_protocol` logged in with the default mock account.
+pub(crate) async fn logged_in(mock: &MockCloud) -> MiCloudProtocol {
+    let mut mi = mock_protocol(mock);
+    mi.login("user@example.com", "password").await.unwrap();
+    mi
+}
@@ -0,0 +1,268 @@
+//! Gateway → sub-device hierarchy built from `Device::parent_id`, e.g. Zigbee
+//! sensors paired with a hub.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{cloud_error, Device, MiCloudProtocol, Result};
+
+#[derive(Serialize, Debug)]
+pub struct DeviceNode {
+    #[serde(flatten)]
+    pub device: Device,
+    /// Sub-devices paired with this device, empty unless it is a gateway
+    pub children: Vec<DeviceNode>,
+}
+
+impl DeviceNode {
+    fn find(&self, did: &str) -> Option<&DeviceNode> {
+        if self.device.did == did {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(did))
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct DeviceTree {
+    /// Devices without a parent, with their sub-devices
+    pub roots: Vec<DeviceNode>,
+    /// Sub-devices whose gateway is not in the list, e.g. a hub shared
+    /// separately or removed from the account
+    pub orphans: Vec<DeviceNode>,
+}
+
+impl DeviceTree {
+    /// Builds the hierarchy from a flat list as returned by `get_devices`,
+    /// keeping the list order among siblings.
+    pub fn build(devices: Vec<Device>) -> Self {
+        let dids: HashSet<String> = devices.iter().map(|d| d.did.clone()).collect();
+        let mut children: HashMap<String, Vec<Device>> = HashMap::new();
+        let mut roots = vec![];
+        let mut orphans = vec![];
+        for device in devices {
+            if device.parent_id.is_empty() || device.parent_id == device.did {
+                roots.push(device);
+            } else if dids.contains(&device.parent_id) {
+                children
+                    .entry(device.parent_id.clone())
+                    .or_default()
+                    .push(device);
+            } else {
+                orphans.push(device);
+            }
+        }
+
+        let mut tree = DeviceTree {
+            roots: attach(roots, &mut children),
+            orphans: attach(orphans, &mut children),
+        };
+        // Whatever is left refers to its parent in a cycle, no root reaches it
+        let mut rest: Vec<Device> = children.into_values().flatten().collect();
+        rest.sort_by(|a, b| a.did.cmp(&b.did));
+        tree.orphans
+            .extend(rest.into_iter().map(|device| DeviceNode {
+                device,
+                children: vec![],
+            }));
+        tree
+    }
+
+    pub fn find(&self, did: &str) -> Option<&DeviceNode> {
+        self.roots
+            .iter()
+            .chain(&self.orphans)
+            .find_map(|node| node.find(did))
+    }
+
+    /// The gateway `did` is paired with, `None` for root devices and orphans.
+    pub fn gateway_of(&self, did: &str) -> Option<&Device> {
+        let parent_id = &self.find(did)?.device.parent_id;
+        if parent_id.is_empty() {
+            return None;
+        }
+        self.find(parent_id).map(|node| &node.device)
+    }
+}
+
+fn attach(devices: Vec<Device>, children: &mut HashMap<String, Vec<Device>>) -> Vec<DeviceNode> {
+    devices
+        .into_iter()
+        .map(|device| {
+            let own = children.remove(&device.did).unwrap_or_default();
+            DeviceNode {
+                children: attach(own, children),
+                device,
+            }
+        })
+        .collect()
+}
+
+impl MiCloudProtocol {
+    /// Lists devices as a gateway → sub-device tree.
+    pub async fn get_device_tree(&self, country: Option<&str>) -> Result<DeviceTree> {
+        Ok(DeviceTree::build(self.get_devices(None, country).await?))
+    }
+
+    /// Sends a miIO call for sub-device `did` to its gateway, which forwards
+    /// it to the device addressed by `sid`.
+    pub async fn call_sub_device(
+        &self,
+        gateway_id: &str,
+        did: &str,
+        method: &str,
+        params: Option<Value>,
+        country: Option<&str>,
+    ) -> Result<Value> {
+        let req = json!({ "method": method, "params": params, "sid": did });
+
+        let country = country.unwrap_or(self.country.as_str());
+        let fallback_msg = format!("Miio call for device {} via {} failed", did, gateway_id);
+        let res = self
+            .request(&format!(r"/home/rpc/{}", gateway_id), req, country)
+            .await?;
+
+        if !res["result"].is_null() {
+            Ok(res["result"].clone())
+        } else {
+            Err(cloud_error(&res, &fallback_msg))
+        }
+    }
+
+    /// Like `call_device`, but sends the call to gateway `parent_id` if one is
+    /// given, e.g. the `parent_id` of a listed device. `None` or an empty id
+    /// calls the device directly.
+    pub async fn call_device_routed(
+        &self,
+        did: &str,
+        parent_id: Option<&str>,
+        method: &str,
+        params: Option<Value>,
+        country: Option<&str>,
+    ) -> Result<Value> {
+        match parent_id {
+            Some(parent_id) if !parent_id.is_empty() && parent_id != did => {
+                self.call_sub_device(parent_id, did, method, params, country)
+                    .await
+            }
+            _ => self.call_device(did, method, params, country).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, logged_in};
+    use crate::Error;
+    use miio_mock::{MockAccount, MockCloud};
+
+    fn sub_device(did: &str, parent_id: &str) -> Value {
+        test_util::device_json(
+            did,
+            json!({ "model": "lumi.sensor_magnet.v2", "parent_id": parent_id, "pid": "3" }),
+        )
+    }
+
+    fn device(did: &str, parent_id: &str) -> Device {
+        serde_json::from_value(sub_device(did, parent_id)).unwrap()
+    }
+
+    fn dids(nodes: &[DeviceNode]) -> Vec<&str> {
+        nodes.iter().map(|n| n.device.did.as_str()).collect()
+    }
+
+    #[test]
+    fn build_tree() {
+        let tree = DeviceTree::build(vec![
+            device("lumi.1", "gw"),
+            device("gw", ""),
+            device("lumi.2", "gone"),
+            device("lamp", ""),
+            device("lumi.3", "gw"),
+            device("a", "b"),
+            device("b", "a"),
+        ]);
+        assert_eq!(dids(&tree.roots), ["gw", "lamp"]);
+        assert_eq!(dids(&tree.roots[0].children), ["lumi.1", "lumi.3"]);
+        assert!(tree.roots[1].children.is_empty());
+        assert_eq!(dids(&tree.orphans), ["lumi.2", "a", "b"]);
+        assert_eq!(tree.gateway_of("lumi.3").unwrap().did, "gw");
+        assert!(tree.gateway_of("gw").is_none());
+        assert!(tree.gateway_of("lumi.2").is_none());
+
+        let value = serde_json::to_value(&tree).unwrap();
+        assert_eq!(value["roots"][0]["did"], "gw");
+        assert_eq!(value["roots"][0]["children"][1]["did"], "lumi.3");
+    }
+
+    #[tokio::test]
+    async fn route_call_through_gateway() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        {
+            let mut state = mock.state();
+            state.devices = vec![
+                sub_device("gw", ""),
+                sub_device("lumi.1", "gw"),
+                sub_device("gw.2", ""),
+            ];
+            state.responses.insert(
+                "/home/rpc/gw".to_string(),
+                json!({ "code": 0, "message": "ok", "result": ["open"] }),
+            );
+        }
+        let mi = logged_in(&mock).await;
+        let devices = mi.get_devices(None, None).await.unwrap();
+        let parent_id = |did: &str| {
+            let device = devices.iter().find(|d| d.did == did).unwrap();
+            Some(device.parent_id.as_str())
+        };
+
+        let params = Some(json!(["status"]));
+        let res = mi
+            .call_device_routed(
+                "lumi.1",
+                parent_id("lumi.1"),
+                "get_prop",
+                params.clone(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res, json!(["open"]));
+        let gateway_request = mock
+            .state()
+            .requests
+            .iter()
+            .find(|r| r.path == "/app/home/rpc/gw")
+            .and_then(|r| r.data.clone())
+            .unwrap();
+        assert_eq!(gateway_request["sid"], "lumi.1");
+
+        // Devices without a gateway are called directly, and their errors kept
+        let err = mi
+            .call_device_routed("gw.2", parent_id("gw.2"), "get_prop", params.clone(), None)
+            .await;
+        assert!(matches!(err, Err(Error::CloudError { .. })));
+        let err = mi
+            .call_device_routed("lumi.1", None, "get_prop", params, None)
+            .await;
+        assert!(matches!(err, Err(Error::CloudError { .. })));
+        let state = mock.state();
+        let rpc: Vec<_> = state
+            .paths()
+            .into_iter()
+            .filter(|p| p.contains("/home/rpc/"))
+            .collect();
+        assert_eq!(
+            rpc,
+            [
+                "/app/home/rpc/gw",
+                "/app/home/rpc/gw.2",
+                "/app/home/rpc/lumi.1"
+            ]
+        );
+    }
+}
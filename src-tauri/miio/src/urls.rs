@@ -0,0 +1,165 @@
+//! Endpoints used by `MiCloudProtocol`, overridable to point the client at
+//! staging mirrors, proxies or local fakes.
+
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+use crate::Result;
+
+const API_HOST: &str = "api.io.mi.com/app";
+
+/// Every URL the login flow and API requests touch.
+///
+/// Missing fields fall back to the Xiaomi defaults when deserializing, so a
+/// config file only has to list the endpoints it changes:
+///
+/// ```json
+/// { "account": "https://account.example.com", "de": "https://proxy.example.com/app" }
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct UrlsConfig {
+    pub cn: String,
+    pub de: String,
+    pub ru: String,
+    pub sg: String,
+    pub tw: String,
+    pub us: String,
+    pub i2: String,
+    /// Defaults to `{account}/pass/serviceLogin`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub login_step1: Option<String>,
+    /// Defaults to `{account}/pass/serviceLoginAuth2`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub login_step2: Option<String>,
+    /// Account host serving captcha images, the `/identity/*` 2FA endpoints
+    /// and the `userId` cookie
+    pub account: String,
+    /// STS host that sets the `serviceToken` cookie
+    pub sts: String,
+}
+
+impl Default for UrlsConfig {
+    fn default() -> Self {
+        UrlsConfig {
+            cn: format!("https://{API_HOST}"),
+            de: format!("https://de.{API_HOST}"),
+            ru: format!("https://ru.{API_HOST}"),
+            sg: format!("https://sg.{API_HOST}"),
+            tw: format!("https://tw.{API_HOST}"),
+            us: format!("https://us.{API_HOST}"),
+            i2: format!("https://i2.{API_HOST}"),
+            login_step1: None,
+            login_step2: None,
+            account: "https://account.xiaomi.com".to_string(),
+            sts: "https://sts.api.io.mi.com".to_string(),
+        }
+    }
+}
+
+impl UrlsConfig {
+    /// Points every endpoint at a single server, with the API under `/app`.
+    pub fn with_base_url(base_url: &str) -> Self {
+        let base_url = base_url.trim_end_matches('/');
+        let api = format!("{base_url}/app");
+        UrlsConfig {
+            cn: api.clone(),
+            de: api.clone(),
+            ru: api.clone(),
+            sg: api.clone(),
+            tw: api.clone(),
+            us: api.clone(),
+            i2: api,
+            login_step1: None,
+            login_step2: None,
+            account: base_url.to_string(),
+            sts: base_url.to_string(),
+        }
+    }
+
+    /// Reads a JSON config, see the type docs for the format.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// API base for a country code, `cn` for unknown codes.
+    pub(crate) fn api(&self, country: &str) -> &str {
+        match country {
+            "de" => &self.de,
+            "ru" => &self.ru,
+            "sg" => &self.sg,
+            "tw" => &self.tw,
+            "us" => &self.us,
+            "i2" => &self.i2,
+            _ => &self.cn,
+        }
+    }
+
+    pub(crate) fn account_url(&self, path: &str) -> String {
+        format!("{}{}", self.account.trim_end_matches('/'), path)
+    }
+
+    pub(crate) fn login_step1_url(&self) -> String {
+        self.login_step1
+            .clone()
+            .unwrap_or_else(|| self.account_url("/pass/serviceLogin"))
+    }
+
+    pub(crate) fn login_step2_url(&self) -> String {
+        self.login_step2
+            .clone()
+            .unwrap_or_else(|| self.account_url("/pass/serviceLoginAuth2"))
+    }
+
+    /// `callback` of the login flow, its redirect sets `serviceToken`.
+    pub(crate) fn sts_callback(&self) -> String {
+        format!("{}/sts", self.sts.trim_end_matches('/'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_config_file() {
+        let path = std::env::temp_dir().join(format!("miio-urls-{}.json", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            r#"{ "account": "http://127.0.0.1:8080/", "de": "http://127.0.0.1:8080/app" }"#,
+        )
+        .unwrap();
+        let urls = UrlsConfig::from_file(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(urls.api("de"), "http://127.0.0.1:8080/app");
+        assert_eq!(urls.api("xx"), "https://api.io.mi.com/app");
+        assert_eq!(
+            urls.account_url("/identity/list"),
+            "http://127.0.0.1:8080/identity/list"
+        );
+        assert_eq!(urls.sts_callback(), "https://sts.api.io.mi.com/sts");
+        assert_eq!(
+            urls.login_step1_url(),
+            "http://127.0.0.1:8080/pass/serviceLogin"
+        );
+        assert_eq!(
+            urls.login_step2_url(),
+            "http://127.0.0.1:8080/pass/serviceLoginAuth2"
+        );
+
+        let urls: UrlsConfig = serde_json::from_str(
+            r#"{ "login_step1": "http://127.0.0.1:9090/login", "account": "http://127.0.0.1:8080" }"#,
+        )
+        .unwrap();
+        assert_eq!(urls.login_step1_url(), "http://127.0.0.1:9090/login");
+        assert_eq!(
+            urls.login_step2_url(),
+            "http://127.0.0.1:8080/pass/serviceLoginAuth2"
+        );
+        assert_eq!(
+            UrlsConfig::default().login_step1_url(),
+            "https://account.xiaomi.com/pass/serviceLogin"
+        );
+    }
+}
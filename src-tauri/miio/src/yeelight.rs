@@ -0,0 +1,177 @@
+//! Yeelight LAN control ("developer mode"), which lets apps on the local
+//! network drive a bulb over TCP without the cloud, and the LAN client for
+//! bulbs that have it turned on.
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::batch::fan_out;
+use crate::{BatchReport, Device, Error, MiCloudProtocol, Result};
+
+mod bulb;
+pub use self::bulb::{BulbProps, Effect, FlowAction, FlowStep, YeelightBulb, YEELIGHT_PORT};
+
+mod discovery;
+pub use self::discovery::{
+    discover_bulbs, discover_bulbs_on, match_bulbs, BulbMatch, DiscoveredBulb,
+};
+
+/// Read by `get_prop`, `"1"` while LAN control is on
+const LAN_CTRL: &str = "lan_ctrl";
+/// Written by `set_ps`
+const CFG_LAN_CTRL: &str = "cfg_lan_ctrl";
+/// Bulbs take a moment to apply the setting before `get_prop` reports it
+const VERIFY_ATTEMPTS: u32 = 3;
+const VERIFY_DELAY: Duration = Duration::from_millis(500);
+
+/// Whether `model` has the Yeelight LAN control setting.
+pub fn supports_lan_control(model: &str) -> bool {
+    model.starts_with("yeelink.light.")
+}
+
+fn check_model(device: &Device) -> Result<()> {
+    if supports_lan_control(&device.model) {
+        Ok(())
+    } else {
+        Err(Error::Unsupported {
+            model: device.model.clone(),
+            feature: "LAN control",
+        })
+    }
+}
+
+impl MiCloudProtocol {
+    pub async fn lan_control_enabled(&self, did: &str, country: Option<&str>) -> Result<bool> {
+        let res = self
+            .call_device(did, "get_prop", Some(json!([LAN_CTRL])), country)
+            .await?;
+        Ok(match &res[0] {
+            Value::String(s) => s == "1",
+            Value::Number(n) => n.as_i64() == Some(1),
+            _ => false,
+        })
+    }
+
+    /// Turns LAN control of a Yeelight bulb on and checks the bulb reports it.
+    pub async fn enable_lan_control(&self, did: &str, country: Option<&str>) -> Result<()> {
+        self.set_lan_control(did, true, country).await
+    }
+
+    pub async fn disable_lan_control(&self, did: &str, country: Option<&str>) -> Result<()> {
+        self.set_lan_control(did, false, country).await
+    }
+
+    pub async fn set_lan_control(
+        &self,
+        did: &str,
+        enabled: bool,
+        country: Option<&str>,
+    ) -> Result<()> {
+        let device = self.get_device(did, country).await?.into_iter().next();
+        let device = device.ok_or_else(|| Error::CloudError {
+            code: -1,
+            message: format!("Device {} not found", did),
+        })?;
+        check_model(&device)?;
+        self.apply_lan_control(did, enabled, country).await
+    }
+
+    /// Turns LAN control on for every online bulb that supports it.
+    pub async fn enable_lan_control_all(
+        &self,
+        concurrency: usize,
+        country: Option<&str>,
+    ) -> Result<BatchReport> {
+        let mut devices = self.get_devices(None, country).await?;
+        devices.retain(|d| d.is_online && supports_lan_control(&d.model));
+        Ok(fan_out(devices, concurrency, |did| async move {
+            self.apply_lan_control(&did, true, country).await?;
+            Ok(json!(true))
+        })
+        .await)
+    }
+
+    async fn apply_lan_control(
+        &self,
+        did: &str,
+        enabled: bool,
+        country: Option<&str>,
+    ) -> Result<()> {
+        let params = json!([CFG_LAN_CTRL, if enabled { "1" } else { "0" }]);
+        self.call_device(did, "set_ps", Some(params), country)
+            .await?;
+        for attempt in 1..=VERIFY_ATTEMPTS {
+            if self.lan_control_enabled(did, country).await? == enabled {
+                return Ok(());
+            }
+            if attempt < VERIFY_ATTEMPTS {
+                tokio::time::sleep(VERIFY_DELAY).await;
+            }
+        }
+        Err(Error::NotApplied(format!(
+            "LAN control of {} is still {}",
+            did,
+            if enabled { "off" } else { "on" }
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{device_json, logged_in};
+    use crate::BatchOutcome;
+    use miio_mock::{MockAccount, MockCloud};
+    use std::collections::HashMap;
+
+    fn device(did: &str, model: &str, online: bool) -> Value {
+        device_json(did, json!({ "model": model, "isOnline": online }))
+    }
+
+    #[tokio::test]
+    async fn toggle_and_verify_lan_control() {
+        let mock = MockCloud::start(MockAccount::default()).await;
+        {
+            let mut state = mock.state();
+            state.devices = vec![
+                device("1", "yeelink.light.color1", true),
+                device("2", "yeelink.light.mono1", true),
+                device("3", "yeelink.light.ceiling4", false),
+                device("4", "zhimi.airpurifier.m1", true),
+            ];
+            for did in ["1", "2", "3", "4"] {
+                let props = HashMap::from([(LAN_CTRL.to_string(), json!("0"))]);
+                state.props.insert(did.to_string(), props);
+            }
+        }
+        let mi = logged_in(&mock).await;
+
+        mi.enable_lan_control("1", None).await.unwrap();
+        assert!(mi.lan_control_enabled("1", None).await.unwrap());
+        mi.disable_lan_control("1", None).await.unwrap();
+        assert!(!mi.lan_control_enabled("1", None).await.unwrap());
+
+        let err = mi.enable_lan_control("4", None).await;
+        assert!(matches!(err, Err(Error::Unsupported { .. })));
+
+        let report = mi.enable_lan_control_all(4, None).await.unwrap();
+        let dids: Vec<_> = report.results.iter().map(|r| r.did.as_str()).collect();
+        assert_eq!(dids, ["1", "2"]);
+        assert_eq!(report.succeeded(), 2);
+        assert_eq!(mock.state().props["3"][LAN_CTRL], "0");
+
+        // A bulb that acknowledges `set_ps` but keeps the old value
+        mock.state().responses.insert(
+            "/home/rpc/2".to_string(),
+            json!({ "code": 0, "message": "ok", "result": ["0"] }),
+        );
+        let report = mi.enable_lan_control_all(4, None).await.unwrap();
+        assert!(matches!(
+            report.results[1].outcome,
+            BatchOutcome::Failed {
+                error: Error::NotApplied(_)
+            }
+        ));
+    }
+}
@@ -0,0 +1,438 @@
+//! Yeelight LAN protocol: newline-delimited JSON over TCP port 55443.
+//!
+//! Requests are `{"id":1,"method":"set_power","params":["on","smooth",500]}`,
+//! replies carry the same `id` with `result` or `error`. While connected the
+//! bulb also pushes `{"method":"props","params":{...}}` whenever its state
+//! changes, which is how `next_props` follows the bulb.
+
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use log::debug;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    time::timeout,
+};
+
+use super::discovery::DiscoveredBulb;
+use crate::{Device, Error, Result};
+
+pub const YEELIGHT_PORT: u16 = 55443;
+
+/// How a change is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    Sudden,
+    /// Fades over the duration, bulbs ignore anything shorter than 30ms
+    Smooth(Duration),
+}
+
+impl Effect {
+    fn params(self) -> [Value; 2] {
+        match self {
+            Effect::Sudden => [json!("sudden"), json!(0)],
+            Effect::Smooth(duration) => [json!("smooth"), json!(duration.as_millis().max(30))],
+        }
+    }
+}
+
+/// What the bulb does once a color flow ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowAction {
+    /// Back to the state before the flow
+    Recover = 0,
+    /// Keep the last step
+    Stay = 1,
+    TurnOff = 2,
+}
+
+/// One step of a color flow. `bright` is 1-100, `None` keeps the brightness.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowStep {
+    Rgb {
+        duration: Duration,
+        rgb: u32,
+        bright: Option<u8>,
+    },
+    Ct {
+        duration: Duration,
+        ct: u16,
+        bright: Option<u8>,
+    },
+    Sleep {
+        duration: Duration,
+    },
+}
+
+impl FlowStep {
+    /// `duration,mode,value,brightness` as `start_cf` expects it
+    fn expression(&self) -> String {
+        let bright = |b: &Option<u8>| b.map_or(-1, i16::from);
+        let (duration, mode, value, bright) = match self {
+            FlowStep::Rgb {
+                duration,
+                rgb,
+                bright: b,
+            } => (duration, 1, *rgb, bright(b)),
+            FlowStep::Ct {
+                duration,
+                ct,
+                bright: b,
+            } => (duration, 2, u32::from(*ct), bright(b)),
+            FlowStep::Sleep { duration } => (duration, 7, 0, 0),
+        };
+        format!(
+            "{},{},{},{}",
+            duration.as_millis().max(50),
+            mode,
+            value,
+            bright
+        )
+    }
+}
+
+/// A `props` notification, the properties that changed and their new values.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BulbProps {
+    /// Cloud `did` of the bulb when it is known
+    pub did: Option<String>,
+    pub props: Map<String, Value>,
+}
+
+/// A TCP connection to a bulb with LAN control turned on.
+///
+/// ```no_run
+/// # async fn run() -> miio::Result<()> {
+/// use miio::{Effect, YeelightBulb};
+///
+/// let mut bulb = YeelightBulb::connect("192.168.1.20:55443".parse().unwrap()).await?;
+/// bulb.set_power(true, Effect::Sudden).await?;
+/// let changed = bulb.next_props().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct YeelightBulb {
+    addr: SocketAddr,
+    did: Option<String>,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    /// Notifications read while waiting for a reply
+    pending: VecDeque<BulbProps>,
+    next_id: u64,
+    timeout: Duration,
+}
+
+impl YeelightBulb {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let stream = timeout(Self::DEFAULT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| Error::Timeout(format!("Connecting to {} timed out", addr)))??;
+        let (reader, writer) = stream.into_split();
+        Ok(YeelightBulb {
+            addr,
+            did: None,
+            lines: BufReader::new(reader).lines(),
+            writer,
+            pending: VecDeque::new(),
+            next_id: 1,
+            timeout: Self::DEFAULT_TIMEOUT,
+        })
+    }
+
+    pub async fn from_discovered(bulb: &DiscoveredBulb) -> Result<Self> {
+        let mut connection = Self::connect(bulb.addr).await?;
+        connection.did = Some(bulb.did());
+        Ok(connection)
+    }
+
+    /// Connects using the `localip` reported by the cloud.
+    pub async fn from_device(device: &Device) -> Result<Self> {
+        let ip = device
+            .localip
+            .parse::<IpAddr>()
+            .map_err(|_| Error::Decode(format!("Device {} has no valid local ip", device.did)))?;
+        let mut connection = Self::connect(SocketAddr::new(ip, YEELIGHT_PORT)).await?;
+        connection.did = Some(device.did.clone());
+        Ok(connection)
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn did(&self) -> Option<&str> {
+        self.did.as_deref()
+    }
+
+    /// Sends a request and waits for the reply with the matching id,
+    /// keeping notifications that arrive in between for `next_props`.
+    pub async fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut request = json!({ "id": id, "method": method, "params": params }).to_string();
+        request.push_str("\r\n");
+        self.writer.write_all(request.as_bytes()).await?;
+
+        let addr = self.addr;
+        let response = timeout(self.timeout, async {
+            loop {
+                let message = self.read_message().await?;
+                if message["method"] == "props" {
+                    let props = self.props(message);
+                    self.pending.push_back(props);
+                } else if message["id"].as_u64() == Some(id) {
+                    return Ok::<_, Error>(message);
+                } else {
+                    debug!("[miio::yeelight] ignoring message {}", message);
+                }
+            }
+        })
+        .await
+        .map_err(|_| {
+            Error::Timeout(format!("Yeelight call '{}' to {} timed out", method, addr))
+        })??;
+
+        if let Some(error) = response.get("error") {
+            return Err(Error::DeviceError {
+                code: error["code"].as_i64().unwrap_or(-1),
+                message: format!(
+                    "Yeelight call '{}' failed: {}",
+                    method,
+                    error["message"].as_str().unwrap_or(&error.to_string())
+                ),
+            });
+        }
+        Ok(response["result"].clone())
+    }
+
+    /// Waits for the next `props` notification, without a timeout.
+    pub async fn next_props(&mut self) -> Result<BulbProps> {
+        if let Some(props) = self.pending.pop_front() {
+            return Ok(props);
+        }
+        loop {
+            let message = self.read_message().await?;
+            if message["method"] == "props" {
+                return Ok(self.props(message));
+            }
+            debug!("[miio::yeelight] ignoring message {}", message);
+        }
+    }
+
+    /// Reads `names`, the bulb reports unknown ones as `""`.
+    pub async fn get_prop(&mut self, names: &[&str]) -> Result<Map<String, Value>> {
+        let result = self.call("get_prop", json!(names)).await?;
+        let values = result.as_array().cloned().unwrap_or_default();
+        Ok(names
+            .iter()
+            .map(|name| name.to_string())
+            .zip(values)
+            .collect())
+    }
+
+    pub async fn set_power(&mut self, on: bool, effect: Effect) -> Result<()> {
+        let [effect, duration] = effect.params();
+        let power = if on { "on" } else { "off" };
+        self.call("set_power", json!([power, effect, duration]))
+            .await
+            .map(drop)
+    }
+
+    /// `bright` is 1-100.
+    pub async fn set_bright(&mut self, bright: u8, effect: Effect) -> Result<()> {
+        let [effect, duration] = effect.params();
+        self.call("set_bright", json!([bright, effect, duration]))
+            .await
+            .map(drop)
+    }
+
+    /// `rgb` is `0xRRGGBB`.
+    pub async fn set_rgb(&mut self, rgb: u32, effect: Effect) -> Result<()> {
+        let [effect, duration] = effect.params();
+        self.call("set_rgb", json!([rgb, effect, duration]))
+            .await
+            .map(drop)
+    }
+
+    /// `ct` is the color temperature in Kelvin, 1700-6500.
+    pub async fn set_ct_abx(&mut self, ct: u16, effect: Effect) -> Result<()> {
+        let [effect, duration] = effect.params();
+        self.call("set_ct_abx", json!([ct, effect, duration]))
+            .await
+            .map(drop)
+    }
+
+    /// Starts a color flow that runs `steps` `count` times, 0 meaning forever.
+    pub async fn start_cf(
+        &mut self,
+        count: u32,
+        action: FlowAction,
+        steps: &[FlowStep],
+    ) -> Result<()> {
+        let expression = steps
+            .iter()
+            .map(FlowStep::expression)
+            .collect::<Vec<_>>()
+            .join(",");
+        // `count` counts state changes, not whole flows
+        let count = count.saturating_mul(steps.len() as u32);
+        self.call("start_cf", json!([count, action as u8, expression]))
+            .await
+            .map(drop)
+    }
+
+    async fn read_message(&mut self) -> Result<Value> {
+        loop {
+            let Some(line) = self.lines.next_line().await? else {
+                return Err(Error::Io(format!("{} closed the connection", self.addr)));
+            };
+            if !line.trim().is_empty() {
+                return Ok(serde_json::from_str(&line)?);
+            }
+        }
+    }
+
+    fn props(&self, mut message: Value) -> BulbProps {
+        let props = match message["params"].take() {
+            Value::Object(props) => props,
+            _ => Map::new(),
+        };
+        BulbProps {
+            did: self.did.clone(),
+            props,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Stands in for a bulb: checks each request against `expected` and
+    /// answers `ok`, pushing a `props` notification before the `set_power`
+    /// reply and rejecting `set_rgb`.
+    async fn fake_bulb(listener: TcpListener, expected: Vec<Value>) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        for params in expected {
+            let line = lines.next_line().await.unwrap().unwrap();
+            let request: Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(request["params"], params, "{}", request["method"]);
+            let id = &request["id"];
+            let reply = match request["method"].as_str().unwrap() {
+                "set_power" => {
+                    let props = json!({ "method": "props", "params": { "power": "on" } });
+                    writer
+                        .write_all(format!("{}\r\n", props).as_bytes())
+                        .await
+                        .unwrap();
+                    json!({ "id": id, "result": ["ok"] })
+                }
+                "set_rgb" => {
+                    json!({ "id": id, "error": { "code": -1, "message": "unsupported method" } })
+                }
+                "get_prop" => json!({ "id": id, "result": ["on", "80"] }),
+                _ => json!({ "id": id, "result": ["ok"] }),
+            };
+            writer
+                .write_all(format!("{}\r\n", reply).as_bytes())
+                .await
+                .unwrap();
+        }
+        let props = json!({ "method": "props", "params": { "bright": "10" } });
+        writer
+            .write_all(format!("{}\r\n", props).as_bytes())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn flow_expression() {
+        let steps = [
+            FlowStep::Rgb {
+                duration: Duration::from_secs(1),
+                rgb: 0xff0000,
+                bright: Some(100),
+            },
+            FlowStep::Ct {
+                duration: Duration::from_millis(500),
+                ct: 2700,
+                bright: None,
+            },
+            FlowStep::Sleep {
+                duration: Duration::from_millis(10),
+            },
+        ];
+        let expressions: Vec<_> = steps.iter().map(FlowStep::expression).collect();
+        assert_eq!(
+            expressions,
+            ["1000,1,16711680,100", "500,2,2700,-1", "50,7,0,0"]
+        );
+    }
+
+    #[tokio::test]
+    async fn commands_and_notifications() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(fake_bulb(
+            listener,
+            vec![
+                json!(["on", "smooth", 500]),
+                json!([50, "sudden", 0]),
+                json!([2700, "smooth", 30]),
+                json!([0xff00ff, "sudden", 0]),
+                json!([4, 2, "1000,1,255,-1,1000,2,6500,100"]),
+                json!(["power", "bright"]),
+            ],
+        ));
+
+        let mut bulb = YeelightBulb::connect(addr).await.unwrap();
+        bulb.did = Some("1385535".to_string());
+        bulb.set_power(true, Effect::Smooth(Duration::from_millis(500)))
+            .await
+            .unwrap();
+        bulb.set_bright(50, Effect::Sudden).await.unwrap();
+        bulb.set_ct_abx(2700, Effect::Smooth(Duration::ZERO))
+            .await
+            .unwrap();
+        let err = bulb.set_rgb(0xff00ff, Effect::Sudden).await;
+        assert!(matches!(err, Err(Error::DeviceError { code: -1, .. })));
+        let steps = [
+            FlowStep::Rgb {
+                duration: Duration::from_secs(1),
+                rgb: 0x0000ff,
+                bright: None,
+            },
+            FlowStep::Ct {
+                duration: Duration::from_secs(1),
+                ct: 6500,
+                bright: Some(100),
+            },
+        ];
+        bulb.start_cf(2, FlowAction::TurnOff, &steps).await.unwrap();
+        let props = bulb.get_prop(&["power", "bright"]).await.unwrap();
+        assert_eq!(props["bright"], "80");
+
+        let first = bulb.next_props().await.unwrap();
+        assert_eq!(first.did.as_deref(), Some("1385535"));
+        assert_eq!(first.props["power"], "on");
+        assert_eq!(bulb.next_props().await.unwrap().props["bright"], "10");
+        server.await.unwrap();
+        assert!(matches!(bulb.next_props().await, Err(Error::Io(_))));
+    }
+}
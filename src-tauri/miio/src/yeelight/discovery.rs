@@ -0,0 +1,275 @@
+//! SSDP-like discovery of Yeelight bulbs that have LAN control turned on.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use log::debug;
+use serde::Serialize;
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+
+use super::bulb::YEELIGHT_PORT;
+use crate::{Device, Result};
+
+const SEARCH: &str = "M-SEARCH * HTTP/1.1\r\n\
+    HOST: 239.255.255.250:1982\r\n\
+    MAN: \"ssdp:discover\"\r\n\
+    ST: wifi_bulb\r\n";
+
+/// A bulb that answered the search, with the state it reported.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredBulb {
+    /// Hex device id, e.g. `0x000000000015243f`
+    pub id: String,
+    pub addr: SocketAddr,
+    /// Short model name such as `color`, not the cloud model
+    pub model: String,
+    pub fw_ver: String,
+    /// Methods the bulb accepts
+    pub support: Vec<String>,
+    pub name: String,
+    pub power: bool,
+    pub bright: u8,
+    /// 1 rgb, 2 color temperature, 3 hsv
+    pub color_mode: u8,
+    pub ct: u16,
+    pub rgb: u32,
+    pub hue: u16,
+    pub sat: u8,
+    /// Only sent by some firmware, callers can fill it in from the ARP table
+    pub mac: Option<String>,
+}
+
+impl DiscoveredBulb {
+    /// Device id in the same format as the cloud `did`.
+    pub fn did(&self) -> String {
+        let hex = self.id.trim_start_matches("0x");
+        u64::from_str_radix(hex, 16).map_or_else(|_| self.id.clone(), |id| id.to_string())
+    }
+
+    pub fn supports(&self, method: &str) -> bool {
+        self.support.iter().any(|m| m == method)
+    }
+
+    /// Parses a search reply or a `NOTIFY` advertisement, `None` if it has no `id`.
+    fn parse(reply: &str, from: SocketAddr) -> Option<Self> {
+        let mut bulb = DiscoveredBulb {
+            id: String::new(),
+            addr: SocketAddr::new(from.ip(), YEELIGHT_PORT),
+            model: String::new(),
+            fw_ver: String::new(),
+            support: vec![],
+            name: String::new(),
+            power: false,
+            bright: 0,
+            color_mode: 0,
+            ct: 0,
+            rgb: 0,
+            hue: 0,
+            sat: 0,
+            mac: None,
+        };
+        for line in reply.lines().skip(1) {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "location" => {
+                    if let Some(addr) = value
+                        .strip_prefix("yeelight://")
+                        .and_then(|a| a.parse().ok())
+                    {
+                        bulb.addr = addr;
+                    }
+                }
+                "id" => bulb.id = value.to_string(),
+                "model" => bulb.model = value.to_string(),
+                "fw_ver" => bulb.fw_ver = value.to_string(),
+                "support" => bulb.support = value.split_whitespace().map(String::from).collect(),
+                "name" => bulb.name = value.to_string(),
+                "power" => bulb.power = value == "on",
+                "bright" => bulb.bright = value.parse().unwrap_or_default(),
+                "color_mode" => bulb.color_mode = value.parse().unwrap_or_default(),
+                "ct" => bulb.ct = value.parse().unwrap_or_default(),
+                "rgb" => bulb.rgb = value.parse().unwrap_or_default(),
+                "hue" => bulb.hue = value.parse().unwrap_or_default(),
+                "sat" => bulb.sat = value.parse().unwrap_or_default(),
+                "mac" => bulb.mac = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        (!bulb.id.is_empty()).then_some(bulb)
+    }
+}
+
+/// Discovered bulb joined with its cloud device.
+#[derive(Serialize, Debug)]
+pub struct BulbMatch {
+    #[serde(flatten)]
+    pub bulb: DiscoveredBulb,
+    /// `None` for bulbs of another account
+    pub device: Option<Device>,
+}
+
+/// Sends the search to `239.255.255.250:1982` and collects replies until `wait` elapses.
+pub async fn discover_bulbs(wait: Duration) -> Result<Vec<DiscoveredBulb>> {
+    discover_bulbs_on(SocketAddr::from(([239, 255, 255, 250], 1982)), wait).await
+}
+
+/// Sends the search to `target`, which can be the multicast group or a single bulb.
+pub async fn discover_bulbs_on(target: SocketAddr, wait: Duration) -> Result<Vec<DiscoveredBulb>> {
+    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+    socket.send_to(SEARCH.as_bytes(), target).await?;
+
+    let deadline = Instant::now() + wait;
+    let mut found: Vec<DiscoveredBulb> = vec![];
+    let mut buf = [0u8; 2048];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, addr) = received?;
+        let reply = String::from_utf8_lossy(&buf[..len]);
+        let Some(bulb) = DiscoveredBulb::parse(&reply, addr) else {
+            debug!("[miio::yeelight] ignoring reply from {}", addr);
+            continue;
+        };
+        // Bulbs answer the search a few times
+        if !found.iter().any(|b| b.id == bulb.id) {
+            found.push(bulb);
+        }
+    }
+    Ok(found)
+}
+
+/// Lowercase hex digits of a MAC address, so `AA:BB:..` matches `aa-bb-..`.
+fn normalize_mac(mac: &str) -> String {
+    mac.chars()
+        .filter(char::is_ascii_hexdigit)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Joins discovered bulbs with the cloud device list by `did`, then by `mac`
+/// if the bulb reported one. The cloud `localip` is the last resort, as it is
+/// stale once DHCP hands the bulb another address.
+pub fn match_bulbs(mut devices: Vec<Device>, bulbs: Vec<DiscoveredBulb>) -> Vec<BulbMatch> {
+    bulbs
+        .into_iter()
+        .map(|bulb| {
+            let did = bulb.did();
+            let mac = bulb
+                .mac
+                .as_deref()
+                .map(normalize_mac)
+                .filter(|m| !m.is_empty());
+            let position = devices
+                .iter()
+                .position(|d| d.did == did)
+                .or_else(|| {
+                    let mac = mac.as_ref()?;
+                    devices.iter().position(|d| normalize_mac(&d.mac) == *mac)
+                })
+                .or_else(|| {
+                    devices
+                        .iter()
+                        .position(|d| d.localip.parse::<IpAddr>().ok() == Some(bulb.addr.ip()))
+                });
+            let device = position.map(|i| devices.swap_remove(i));
+            BulbMatch { bulb, device }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPLY: &str = "HTTP/1.1 200 OK\r\n\
+        Cache-Control: max-age=3600\r\n\
+        Location: yeelight://127.0.0.1:55443\r\n\
+        Server: POSIX UPnP/1.0 YGLC/1\r\n\
+        id: 0x000000000015243f\r\n\
+        model: color\r\n\
+        fw_ver: 18\r\n\
+        support: get_prop set_power set_bright set_rgb set_ct_abx start_cf\r\n\
+        power: on\r\n\
+        bright: 100\r\n\
+        color_mode: 2\r\n\
+        ct: 4000\r\n\
+        rgb: 16711680\r\n\
+        hue: 100\r\n\
+        sat: 35\r\n\
+        name: desk\r\n";
+
+    fn device(did: &str, localip: &str) -> Device {
+        Device {
+            did: did.to_string(),
+            localip: localip.to_string(),
+            model: "yeelink.light.color1".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn match_by_mac_after_ip_change() {
+        let reply = REPLY
+            .replace("15243f", "abcdef")
+            .replace("name: desk", "mac: 7C:49:EB:01:02:03");
+        let bulb = DiscoveredBulb::parse(&reply, "127.0.0.1:1982".parse().unwrap()).unwrap();
+        // The cloud still has the old address, which now belongs to another bulb
+        let devices = vec![
+            device("1", "127.0.0.1"),
+            Device {
+                mac: "7c49eb010203".to_string(),
+                ..device("2", "192.168.1.2")
+            },
+        ];
+        let matched = match_bulbs(devices, vec![bulb]);
+        assert_eq!(matched[0].device.as_ref().unwrap().did, "2");
+    }
+
+    #[tokio::test]
+    async fn discover_and_match() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+            assert!(String::from_utf8_lossy(&buf[..len]).contains("ST: wifi_bulb"));
+            for reply in [REPLY, REPLY, "HTTP/1.1 200 OK\r\n\r\n"] {
+                socket.send_to(reply.as_bytes(), client).await.unwrap();
+            }
+        });
+
+        let bulbs = discover_bulbs_on(addr, Duration::from_millis(300))
+            .await
+            .unwrap();
+        server.await.unwrap();
+        assert_eq!(bulbs.len(), 1);
+        let bulb = &bulbs[0];
+        assert_eq!(bulb.did(), "1385535");
+        assert_eq!(bulb.addr, "127.0.0.1:55443".parse().unwrap());
+        assert_eq!((bulb.power, bulb.bright, bulb.ct), (true, 100, 4000));
+        assert!(bulb.supports("start_cf") && !bulb.supports("set_hsv"));
+
+        let other = DiscoveredBulb::parse(
+            &REPLY
+                .replace("15243f", "abcdef")
+                .replace("127.0.0.1", "10.0.0.7"),
+            addr,
+        )
+        .unwrap();
+        let devices = vec![
+            device("1", "10.0.0.7"),
+            device("1385535", "192.168.1.2"),
+            device("2", "10.0.0.8"),
+        ];
+        let matched = match_bulbs(devices, vec![bulb.clone(), other]);
+        let dids: Vec<_> = matched
+            .iter()
+            .map(|m| m.device.as_ref().map(|d| d.did.as_str()))
+            .collect();
+        assert_eq!(dids, [Some("1385535"), Some("1")]);
+    }
+}
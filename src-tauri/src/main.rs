@@ -5,77 +5,493 @@ extern crate anyhow;
 extern crate miio;
 extern crate serde_json;
 
-use miio::{Device, MiCloudProtocol};
+use miio::{
+    AccountInfo, AccountRegistry, BatchReport, BulbMatch, Device, DeviceListOptions,
+    DeviceSelector, DeviceTree, DiscoveryReport, Error, ExportFormat, Home, MergedDevices,
+    MiCloudProtocol, MiCloudSession, MiotActionRequest, MiotActionResult, MiotPropertyRequest,
+    MiotPropertyResult, MiotSetPropertyRequest, MiotSpec, MiotSpecClient, PollEvent, Poller,
+    PropertyId, Scene, UrlsConfig, DEFAULT_BATCH_CONCURRENCY,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{cell::UnsafeCell, mem::MaybeUninit, str::FromStr, sync::Once};
-use tauri::{Emitter, Listener};
+use std::{fs, path::PathBuf, str::FromStr, time::Duration};
+use tauri::{AppHandle, Emitter, Listener, Manager, State};
 use tauri_plugin_log::{Builder, Target, TargetKind};
+use tokio::sync::RwLock;
 
-pub static mut MI_CLOUD_PROTOCOL_UNSAFE: MaybeUninit<UnsafeCell<MiCloudProtocol>> =
-    MaybeUninit::uninit();
-static ONCE: Once = Once::new();
+/// Commands take a read lock; only finished logins and account changes write.
+type AccountsState = RwLock<AccountRegistry>;
 
-//TODO: rm .map_err(|_| ()) https://tauri.app/v1/guides/features/command/#error-handling
+/// Shares the challenge state of every registry protocol outside the lock: an
+/// automatic re-login can ask for a captcha while a command holds a read guard,
+/// and a queued writer would then keep the answer from taking one.
+struct Challenges(MiCloudProtocol);
+
+const ACCOUNTS_FILE_NAME: &str = "accounts.json";
+/// Optional `UrlsConfig` in the app config dir, for proxies and staging servers
+const ENDPOINTS_FILE_NAME: &str = "endpoints.json";
+/// How often watched device properties are polled while the device is online
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct SessionInfo {
+    email: Option<String>,
+    country: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedAccounts {
+    active: Option<String>,
+    accounts: Vec<MiCloudSession>,
+}
+
+fn accounts_path(app: &AppHandle) -> Option<PathBuf> {
+    app.path()
+        .app_data_dir()
+        .ok()
+        .map(|dir| dir.join(ACCOUNTS_FILE_NAME))
+}
+
+fn load_accounts(app: &AppHandle) -> Option<SavedAccounts> {
+    let data = fs::read_to_string(accounts_path(app)?).ok()?;
+    serde_json::from_str(&data).ok()
+}
+
+fn save_accounts(app: &AppHandle, registry: &AccountRegistry) -> Result<(), Error> {
+    let path = accounts_path(app).ok_or(Error::Io("App data dir is not available".into()))?;
+    let saved = SavedAccounts {
+        active: registry.active().map(str::to_string),
+        accounts: registry.sessions(),
+    };
+    if saved.accounts.is_empty() {
+        return match fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        };
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string(&saved)?)?;
+    Ok(())
+}
+
+/// Inserts a logged-in protocol and persists the registry.
+async fn store_account(
+    app: &AppHandle,
+    state: &AccountsState,
+    protocol: MiCloudProtocol,
+) -> Result<AccountInfo, Error> {
+    let mut registry = state.write().await;
+    let user_id = registry.insert(protocol)?;
+    // A failed save only costs a re-login on the next start
+    let _ = save_accounts(app, &registry);
+    registry
+        .list()
+        .into_iter()
+        .find(|a| a.user_id == user_id)
+        .ok_or(Error::UnknownAccount(user_id))
+}
 
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 #[tauri::command]
-async fn login(email: String, password: String, country: Option<String>) -> Result<(), String> {
-    unsafe {
-        let mut guard = &mut *MI_CLOUD_PROTOCOL_UNSAFE.assume_init_ref().get();
-        if let Some(c) = country {
-            guard.set_country(&c);
-        }
-        guard
-            .login(email.as_str(), password.as_str())
-            .await
-            .map_err(|err| err.to_string())
+async fn add_account(
+    app: AppHandle,
+    state: State<'_, AccountsState>,
+    email: String,
+    password: String,
+    country: Option<String>,
+) -> Result<AccountInfo, Error> {
+    // Log in outside the lock so other commands are not blocked while the
+    // user solves a captcha or 2FA challenge
+    let mut protocol = state.read().await.new_protocol();
+    if let Some(c) = country {
+        protocol.set_country(&c);
     }
+    protocol.login(email.as_str(), password.as_str()).await?;
+    store_account(&app, &state, protocol).await
 }
 
+/// Logs into an existing account again, e.g. after its session expired.
 #[tauri::command]
-async fn get_countries() -> Vec<Vec<&'static str>> {
-    unsafe {
-        let mut guard = &*MI_CLOUD_PROTOCOL_UNSAFE.assume_init_ref().get();
-        guard.get_available_countries()
-    }
+async fn login_account(
+    app: AppHandle,
+    state: State<'_, AccountsState>,
+    account: String,
+    password: String,
+) -> Result<AccountInfo, Error> {
+    let (mut protocol, email) = {
+        let registry = state.read().await;
+        let info = registry
+            .list()
+            .into_iter()
+            .find(|a| a.user_id == account)
+            .ok_or_else(|| Error::UnknownAccount(account.clone()))?;
+        let mut protocol = registry.new_protocol();
+        protocol.set_country(&info.country);
+        (protocol, info.username.unwrap_or(account))
+    };
+    protocol.login(email.as_str(), password.as_str()).await?;
+    store_account(&app, &state, protocol).await
 }
 
 #[tauri::command]
-async fn set_country(country: String) {
-    unsafe {
-        let mut guard = &mut *MI_CLOUD_PROTOCOL_UNSAFE.assume_init_ref().get();
-        guard.set_country(&country)
-    }
+async fn list_accounts(state: State<'_, AccountsState>) -> Result<Vec<AccountInfo>, Error> {
+    Ok(state.read().await.list())
 }
 
 #[tauri::command]
-async fn get_devices() -> Result<Vec<Device>, ()> {
-    unsafe {
-        let mut guard = &*MI_CLOUD_PROTOCOL_UNSAFE.assume_init_ref().get();
-        guard.get_devices(None, None).await.map_err(|_| ())
-    }
+async fn switch_account(
+    app: AppHandle,
+    state: State<'_, AccountsState>,
+    account: String,
+) -> Result<(), Error> {
+    let mut registry = state.write().await;
+    registry.switch(&account)?;
+    let _ = save_accounts(&app, &registry);
+    Ok(())
+}
+
+/// Removes an account, the active one when `account` is omitted.
+#[tauri::command]
+async fn remove_account(
+    app: AppHandle,
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+) -> Result<(), Error> {
+    let mut registry = state.write().await;
+    registry.remove(account.as_deref())?;
+    save_accounts(&app, &registry)
+}
+
+#[tauri::command]
+async fn get_session(state: State<'_, AccountsState>) -> Result<Option<SessionInfo>, Error> {
+    let registry = state.read().await;
+    Ok(registry
+        .get(None)
+        .ok()
+        .and_then(|protocol| protocol.get_session())
+        .map(|session| SessionInfo {
+            email: session.username,
+            country: session.country,
+        }))
 }
 
 #[tauri::command]
-async fn get_device(did: String) -> Result<Vec<Device>, ()> {
-    unsafe {
-        let mut guard = &*MI_CLOUD_PROTOCOL_UNSAFE.assume_init_ref().get();
-        guard.get_device(&did, None).await.map_err(|_| ())
+async fn get_countries() -> Result<Vec<Vec<&'static str>>, Error> {
+    Ok(MiCloudProtocol::default().get_available_countries())
+}
+
+#[tauri::command]
+async fn set_country(
+    app: AppHandle,
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    country: String,
+) -> Result<(), Error> {
+    let mut registry = state.write().await;
+    registry.get_mut(account.as_deref())?.set_country(&country);
+    let _ = save_accounts(&app, &registry);
+    Ok(())
+}
+
+/// Lists devices, including shared ones and other homes when `options` ask for them.
+#[tauri::command]
+async fn get_devices(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    options: Option<DeviceListOptions>,
+) -> Result<Vec<Device>, Error> {
+    let registry = state.read().await;
+    let protocol = registry.get(account.as_deref())?;
+    match options {
+        Some(options) => protocol.list_devices(&options, None).await,
+        None => protocol.get_devices(None, None).await,
     }
 }
 
+/// Homes with their rooms, for grouping `get_devices` by home and room.
+#[tauri::command]
+async fn get_homes(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+) -> Result<Vec<Home>, Error> {
+    let registry = state.read().await;
+    registry.get(account.as_deref())?.get_homes(None).await
+}
+
+/// Devices as a gateway → sub-device tree, with sub-devices of missing gateways as orphans.
+#[tauri::command]
+async fn get_device_tree(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+) -> Result<DeviceTree, Error> {
+    let registry = state.read().await;
+    registry
+        .get(account.as_deref())?
+        .get_device_tree(None)
+        .await
+}
+
+/// Scenes and automations of `home_id`, or of every home. With `did`, only
+/// those the device triggers or is controlled by.
 #[tauri::command]
-async fn call_device(did: String, method: String, params: Option<String>) -> Result<Value, String> {
-    unsafe {
-        let mut guard = &*MI_CLOUD_PROTOCOL_UNSAFE.assume_init_ref().get();
-        let params = params
-            .map(|params| Value::from_str(params.as_str()).map_err(|err| err.to_string()))
-            .transpose()?;
-        guard
-            .call_device(&did, &method, params, None)
-            .await
-            .map_err(|err| err.to_string())
+async fn get_scenes(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    home_id: Option<String>,
+    did: Option<String>,
+) -> Result<Vec<Scene>, Error> {
+    let registry = state.read().await;
+    let mut scenes = registry
+        .get(account.as_deref())?
+        .get_scenes(home_id.as_deref(), None)
+        .await?;
+    if let Some(did) = did {
+        scenes.retain(|scene| scene.references(&did));
     }
+    Ok(scenes)
+}
+
+#[tauri::command]
+async fn run_scene(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    scene: Scene,
+) -> Result<(), Error> {
+    let registry = state.read().await;
+    registry
+        .get(account.as_deref())?
+        .run_scene(&scene, None)
+        .await
+}
+
+#[tauri::command]
+async fn set_scene_enabled(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    scene: Scene,
+    enabled: bool,
+) -> Result<(), Error> {
+    let registry = state.read().await;
+    registry
+        .get(account.as_deref())?
+        .set_scene_enabled(&scene, enabled, None)
+        .await
+}
+
+/// Devices of every account in one list, tagged with `user_id`.
+#[tauri::command]
+async fn get_all_devices(state: State<'_, AccountsState>) -> Result<MergedDevices, Error> {
+    Ok(state.read().await.get_all_devices().await)
+}
+
+/// Fetches a single device, with the bind key if it is a BLE device.
+#[tauri::command]
+async fn get_device(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    did: String,
+) -> Result<Vec<Device>, Error> {
+    let registry = state.read().await;
+    let protocol = registry.get(account.as_deref())?;
+    let mut devices = protocol.get_device(&did, None).await?;
+    protocol.fill_ble_beacon_keys(&mut devices, None).await?;
+    Ok(devices)
+}
+
+/// Sends a miIO call to `did`, through its gateway if `parent_id` is given.
+#[tauri::command]
+async fn call_device(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    did: String,
+    parent_id: Option<String>,
+    method: String,
+    params: Option<String>,
+) -> Result<Value, Error> {
+    let params = params
+        .map(|params| Value::from_str(params.as_str()))
+        .transpose()?;
+    let registry = state.read().await;
+    registry
+        .get(account.as_deref())?
+        .call_device_routed(&did, parent_id.as_deref(), &method, params, None)
+        .await
+}
+
+/// Sends one miIO call to every device `selector` matches, reporting each result.
+#[tauri::command]
+async fn call_devices(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    selector: DeviceSelector,
+    method: String,
+    params: Option<String>,
+    concurrency: Option<usize>,
+) -> Result<BatchReport, Error> {
+    let params = params
+        .map(|params| Value::from_str(params.as_str()))
+        .transpose()?;
+    let concurrency = concurrency.unwrap_or(DEFAULT_BATCH_CONCURRENCY);
+    let registry = state.read().await;
+    registry
+        .get(account.as_deref())?
+        .call_devices(&selector, &method, params, concurrency, None)
+        .await
+}
+
+#[tauri::command]
+async fn get_lan_control(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    did: String,
+) -> Result<bool, Error> {
+    let registry = state.read().await;
+    registry
+        .get(account.as_deref())?
+        .lan_control_enabled(&did, None)
+        .await
+}
+
+/// Turns LAN control of a Yeelight bulb on or off, failing if it does not stick.
+#[tauri::command]
+async fn set_lan_control(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    did: String,
+    enabled: bool,
+) -> Result<(), Error> {
+    let registry = state.read().await;
+    registry
+        .get(account.as_deref())?
+        .set_lan_control(&did, enabled, None)
+        .await
+}
+
+/// Turns LAN control on for every online Yeelight bulb.
+#[tauri::command]
+async fn enable_lan_control_all(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+) -> Result<BatchReport, Error> {
+    let registry = state.read().await;
+    registry
+        .get(account.as_deref())?
+        .enable_lan_control_all(DEFAULT_BATCH_CONCURRENCY, None)
+        .await
+}
+
+/// Polls `properties` of `did` with `account`, or the account active at each
+/// poll if `None`, see `device_property_changed`.
+#[tauri::command]
+fn watch_device_properties(
+    poller: State<'_, Poller>,
+    account: Option<String>,
+    did: String,
+    properties: Vec<PropertyId>,
+) {
+    poller.watch(&did, account.as_deref(), properties);
+}
+
+#[tauri::command]
+fn unwatch_device_properties(poller: State<'_, Poller>, did: String) {
+    poller.unwatch(&did);
+}
+
+#[tauri::command]
+async fn get_properties(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    params: Vec<MiotPropertyRequest>,
+) -> Result<Vec<MiotPropertyResult>, Error> {
+    let registry = state.read().await;
+    registry
+        .get(account.as_deref())?
+        .get_properties(&params, None)
+        .await
+}
+
+#[tauri::command]
+async fn set_properties(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    params: Vec<MiotSetPropertyRequest>,
+) -> Result<Vec<MiotPropertyResult>, Error> {
+    let registry = state.read().await;
+    registry
+        .get(account.as_deref())?
+        .set_properties(&params, None)
+        .await
+}
+
+#[tauri::command]
+async fn call_action(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    params: MiotActionRequest,
+) -> Result<MiotActionResult, Error> {
+    let registry = state.read().await;
+    registry
+        .get(account.as_deref())?
+        .call_action(&params, None)
+        .await
+}
+
+/// Writes the device tokens and BLE bind keys of an account to `path` in `format`.
+#[tauri::command]
+async fn export_tokens(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+    format: ExportFormat,
+    path: PathBuf,
+    options: Option<DeviceListOptions>,
+) -> Result<(), Error> {
+    let registry = state.read().await;
+    let protocol = registry.get(account.as_deref())?;
+    let options = DeviceListOptions {
+        ble_keys: true,
+        ..options.unwrap_or_default()
+    };
+    let devices = protocol.list_devices(&options, None).await?;
+    fs::write(path, miio::export_devices(&devices, format)?)?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_device_spec(
+    spec_client: State<'_, MiotSpecClient>,
+    model: String,
+) -> Result<MiotSpec, Error> {
+    spec_client.get_spec(&model).await
+}
+
+#[tauri::command]
+async fn discover_devices(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+) -> Result<DiscoveryReport, Error> {
+    let discovered = miio::discover(Duration::from_secs(3)).await?;
+    let registry = state.read().await;
+    let devices = registry
+        .get(account.as_deref())?
+        .get_devices(None, None)
+        .await?;
+    Ok(miio::match_devices(devices, &discovered))
+}
+
+#[tauri::command]
+async fn discover_bulbs(
+    state: State<'_, AccountsState>,
+    account: Option<String>,
+) -> Result<Vec<BulbMatch>, Error> {
+    let bulbs = miio::discover_bulbs(Duration::from_secs(3)).await?;
+    let registry = state.read().await;
+    let devices = registry
+        .get(account.as_deref())?
+        .get_devices(None, None)
+        .await?;
+    Ok(miio::match_bulbs(devices, bulbs))
 }
 
 fn main() {
@@ -92,70 +508,150 @@ fn main() {
         .plugin(tauri_plugin_http::init())
         .plugin(tauri_plugin_shell::init())
         .invoke_handler(tauri::generate_handler![
-            login,
+            add_account,
+            login_account,
+            list_accounts,
+            switch_account,
+            remove_account,
+            get_session,
             get_countries,
             set_country,
             get_device,
             get_devices,
-            call_device
+            get_homes,
+            get_device_tree,
+            get_scenes,
+            run_scene,
+            set_scene_enabled,
+            get_all_devices,
+            call_device,
+            call_devices,
+            get_lan_control,
+            set_lan_control,
+            enable_lan_control_all,
+            watch_device_properties,
+            unwatch_device_properties,
+            get_properties,
+            set_properties,
+            call_action,
+            export_tokens,
+            get_device_spec,
+            discover_devices,
+            discover_bulbs
         ])
         .setup(|app| {
-            ONCE.call_once(|| unsafe {
-                MI_CLOUD_PROTOCOL_UNSAFE.write(UnsafeCell::new(MiCloudProtocol::new()));
-            });
-
             let app_handle = app.handle();
 
+            let mut protocol = MiCloudProtocol::new();
+            let endpoints = app.path().app_config_dir()?.join(ENDPOINTS_FILE_NAME);
+            if endpoints.exists() {
+                // A broken file should not keep the app from starting
+                match UrlsConfig::from_file(&endpoints) {
+                    Ok(urls) => protocol._override_urls(urls),
+                    Err(e) => log::error!(
+                        "Ignoring {}, using the default endpoints: {}",
+                        endpoints.display(),
+                        e
+                    ),
+                }
+            }
+            protocol._set_captcha_handler(Box::new({
+                let app_handle = app_handle.clone();
+                move |x| {
+                    let _ = app_handle.emit("captcha_requested", x);
+                }
+            }));
+            protocol._set_two_factor_handler(Box::new({
+                let app_handle = app_handle.clone();
+                move |payload, error| {
+                    let _ = app_handle.emit("two_factor_requested", (payload, error));
+                }
+            }));
+            protocol._set_session_handler(Box::new({
+                let app_handle = app_handle.clone();
+                move |_| {
+                    // The re-logged-in protocol lives in the registry, so saving
+                    // the registry persists its new serviceToken
+                    let app_handle = app_handle.clone();
+                    tauri::async_runtime::spawn(async move {
+                        let state = app_handle.state::<AccountsState>();
+                        let _ = save_accounts(&app_handle, &*state.read().await);
+                    });
+                }
+            }));
+            let mut registry = AccountRegistry::new(protocol);
+            if let Some(saved) = load_accounts(app_handle) {
+                registry.restore(saved.accounts, saved.active);
+            }
+            app.manage(Challenges(registry.new_protocol()));
+            app.manage::<AccountsState>(RwLock::new(registry));
+
+            app.manage(MiotSpecClient::new().with_cache(
+                app.path().app_cache_dir()?.join("miot-spec"),
+                MiotSpecClient::DEFAULT_MAX_AGE,
+            ));
+
+            app.manage(Poller::new(POLL_INTERVAL));
             tauri::async_runtime::spawn({
                 let app_handle = app_handle.clone();
                 async move {
-                    unsafe {
-                        let mut guard = &mut *MI_CLOUD_PROTOCOL_UNSAFE.assume_init_ref().get();
-                        guard._set_captcha_handler(Box::new(move |x| {
-                            let app_handle = app_handle.clone();
-                            let _ = app_handle.emit("captcha_requested", x);
-                        }));
+                    let poller = app_handle.state::<Poller>();
+                    let accounts = app_handle.state::<AccountsState>();
+                    loop {
+                        poller.wait_due().await;
+                        for account in poller.due_accounts() {
+                            let account = account.as_deref();
+                            // Polls run on a clone so commands that need the
+                            // registry lock are not held up by a slow pass
+                            let protocol = accounts.read().await.get(account).cloned();
+                            let events = match protocol {
+                                Ok(protocol) => poller.poll_due(account, &protocol, None).await,
+                                Err(_) => poller.skip_due(account),
+                            };
+                            for event in events {
+                                let _ = match event {
+                                    PollEvent::Changed(change) => {
+                                        app_handle.emit("device_property_changed", change)
+                                    }
+                                    status => app_handle.emit("device_poll_status", status),
+                                };
+                            }
+                        }
                     }
                 }
             });
-            app_handle.listen("captcha_solved", move |event| {
-                tauri::async_runtime::spawn(async move {
-                    unsafe {
-                        let guard = &*MI_CLOUD_PROTOCOL_UNSAFE.assume_init_ref().get();
+
+            // Protocols from `new_protocol` share the template's challenge
+            // state, so solving on `Challenges` resumes the login in progress
+            app_handle.listen("captcha_solved", {
+                let app_handle = app_handle.clone();
+                move |event| {
+                    let app_handle = app_handle.clone();
+                    tauri::async_runtime::spawn(async move {
+                        let challenges = app_handle.state::<Challenges>();
                         let pl = serde_json::from_str::<String>(event.payload()).unwrap();
                         if pl.eq("CANCEL") {
-                            guard.captcha_cancel().await
+                            challenges.0.captcha_cancel().await
                         } else {
-                            guard.captcha_solve(pl.as_str()).await
+                            challenges.0.captcha_solve(pl.as_str()).await
                         }
-                    }
-                });
-            });
-
-            tauri::async_runtime::spawn({
-                let app_handle = app_handle.clone();
-                async move {
-                    unsafe {
-                        let mut guard = &mut *MI_CLOUD_PROTOCOL_UNSAFE.assume_init_ref().get();
-                        guard._set_two_factor_handler(Box::new(move |payload, error| {
-                            let app_handle = app_handle.clone();
-                            let _ = app_handle.emit("two_factor_requested", (payload, error));
-                        }));
-                    }
+                    });
                 }
             });
-            app_handle.listen("two_factor_solved", move |event| {
-                tauri::async_runtime::spawn(async move {
-                    unsafe {
-                        let guard = &*MI_CLOUD_PROTOCOL_UNSAFE.assume_init_ref().get();
+            app_handle.listen("two_factor_solved", {
+                let app_handle = app_handle.clone();
+                move |event| {
+                    let app_handle = app_handle.clone();
+                    tauri::async_runtime::spawn(async move {
+                        let challenges = app_handle.state::<Challenges>();
                         let pl = serde_json::from_str::<String>(event.payload()).unwrap();
                         if pl.eq("CANCEL") {
-                            guard.two_factor_cancel().await
+                            challenges.0.two_factor_cancel().await
                         } else {
-                            guard.two_factor_solve(pl.as_str()).await
+                            challenges.0.two_factor_solve(pl.as_str()).await
                         }
-                    }
-                });
+                    });
+                }
             });
 
             Ok(())
@@ -2,6 +2,7 @@ import { ApplicationConfig, inject, provideAppInitializer } from '@angular/core'
 import { provideRouter } from '@angular/router'
 import { routes } from './app.routes'
 import { ConfigService } from './config.service'
+import { AuthService } from './auth.service'
 import {
   provideTanStackQuery,
   QueryClient,
@@ -22,5 +23,6 @@ export const appConfig: ApplicationConfig = {
       const initializerFn = ConfigService.factory(inject(ConfigService))
       return initializerFn()
     }),
+    provideAppInitializer(() => inject(AuthService).restoreSession()),
   ],
 }
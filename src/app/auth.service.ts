@@ -16,6 +16,16 @@ export class AuthService {
   user = toSignal(this.user$)
   loggedIn$ = this.user$.pipe(map(Boolean))
 
+  async restoreSession() {
+    const session = await this.miService.getSession()
+    if (session) this.user$.next({ ...session, email: session.email ?? '' })
+  }
+
+  async logout() {
+    await this.miService.logout()
+    this.user$.next(null)
+  }
+
   async setCountry(country: string) {
     const res = this.miService.setCountry(country)
     this.user$.next({ ...this.user$.value!, country })
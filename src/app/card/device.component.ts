@@ -43,6 +43,9 @@ import { injectQuery } from '@tanstack/angular-query-experimental'
         <div class="card-body">
           <h2 class="card-title">
             {{ device.name }}
+            @if (device.ownership === 'shared') {
+              <div class="badge badge-outline self-start">Shared</div>
+            }
 
             <div class="tooltip self-start" data-tip="Refresh">
               <button
@@ -68,6 +71,16 @@ import { injectQuery } from '@tanstack/angular-query-experimental'
           <p>MAC: {{ device.mac }}</p>
           <p>Model: {{ device.model }}</p>
           <p>Token: {{ device.token }}</p>
+          @if (device.extra.fw_version) {
+            <p>Firmware: {{ device.extra.fw_version }}</p>
+          }
+          @if (device.ble_key) {
+            <p>BLE key: {{ device.ble_key }}</p>
+          }
+
+          @if (livePower(); as power) {
+            <p>Power: {{ power }}</p>
+          }
 
           <label
             class="label cursor-pointer justify-start text-inherit"
@@ -143,11 +156,28 @@ export class DeviceComponent {
     if (lanModeAvailable && !lanModeLoading) {
       this.lanModeLoading.set(true)
       this.miService
-        .getProp({ did: device.did, name: ['lan_ctrl'] })
-        .then((res) => this.lanMode.set((res as any)[0] === '1'))
+        .getLanControl(device.did)
+        .then((enabled) => this.lanMode.set(enabled))
         .finally(() => this.lanModeLoading.set(false))
     }
   })
+  /** Polled while the card is shown, see `MiService.watchProperties` */
+  livePower = signal<string | null>(null)
+  private livePowerEffect = effect((onCleanup) => {
+    const did = this.deviceComputed().did
+    if (!this.lanModeAvailable()) return
+    this.miService.watchProperties(did, ['power'])
+    const unlisten = this.miService.onPropertyChanged((change) => {
+      if (change.did === did && change.property === 'power') {
+        this.livePower.set(change.new)
+      }
+    })
+    onCleanup(() => {
+      unlisten.then((fn) => fn())
+      this.miService.unwatchProperties(did)
+    })
+  })
+
   lanModeChange() {
     const lanModeLoading = this.lanModeLoading()
     if (lanModeLoading) return
@@ -155,11 +185,7 @@ export class DeviceComponent {
     const device = this.deviceComputed()
     const lanMode = this.lanMode()
     this.miService
-      .setProp({
-        did: device.did,
-        name: 'cfg_lan_ctrl',
-        value: lanMode ? '0' : '1',
-      })
+      .setLanControl(device.did, !lanMode)
       .then(() => this.lanMode.set(!lanMode))
       .finally(() => this.lanModeLoading.set(false))
   }
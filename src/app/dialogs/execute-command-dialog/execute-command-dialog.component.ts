@@ -50,6 +50,17 @@ import { FormBuilder, FormsModule, ReactiveFormsModule } from '@angular/forms'
           placeholder="Params"
         ></textarea>
 
+        @if (device()?.model; as model) {
+          <label class="label cursor-pointer justify-start text-inherit">
+            <input
+              type="checkbox"
+              class="checkbox checkbox-sm"
+              [formControlName]="'allOfModel'"
+            />
+            Send to every {{ model }}
+          </label>
+        }
+
         <textarea
           [readonly]="true"
           class="textarea w-full"
@@ -79,7 +90,12 @@ import { FormBuilder, FormsModule, ReactiveFormsModule } from '@angular/forms'
 })
 export class ExecuteCommandDialogComponent {
   fb = inject(FormBuilder)
-  device = model<{ did: number | string; name: string } | null>(null)
+  device = model<{
+    did: number | string
+    name: string
+    model?: string
+    parent_id?: string
+  } | null>(null)
   did = computed(() => this.device()?.did)
   visible = computed(() => !!this.device())
 
@@ -90,6 +106,7 @@ export class ExecuteCommandDialogComponent {
   form = this.fb.group({
     method: '',
     params: '',
+    allOfModel: false,
     result: '' as any,
   })
 
@@ -100,11 +117,22 @@ export class ExecuteCommandDialogComponent {
   openCloseEffect = effect(() => this.visible() && this.form.reset())
 
   callDeviceMutation = injectMutation(() => ({
-    mutationFn: (data: {
+    mutationFn: ({
+      model,
+      ...data
+    }: {
       did: string
+      parentId?: string
       method: string
       params?: string | null
-    }) => this.miService.callDevice(data),
+      model?: string
+    }) =>
+      model
+        ? this.miService.callDevices({
+            ...data,
+            selector: { by: 'model', pattern: model },
+          })
+        : this.miService.callDevice(data),
     onSuccess: () => this.success.emit(),
   }))
 
@@ -117,15 +145,17 @@ export class ExecuteCommandDialogComponent {
     const result = this.form.controls.result
 
     if (isPending) return result.setValue('Loading...')
-    if (isError) return result.setValue(error || 'Error')
+    if (isError) return result.setValue(error?.message || 'Error')
     return result.setValue(JSON.stringify(data))
   })
 
   executeCommand() {
     if (this.callDeviceMutation.isPending()) return
     const did = this.did()?.toString()
-    const { method, params } = this.form.value
+    const { method, params, allOfModel } = this.form.value
     if (!did || !method) return
-    this.callDeviceMutation.mutate({ did, method, params })
+    const model = allOfModel ? this.device()?.model : undefined
+    const parentId = this.device()?.parent_id
+    this.callDeviceMutation.mutate({ did, parentId, method, params, model })
   }
 }
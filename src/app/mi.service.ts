@@ -1,13 +1,61 @@
 import { computed, Injectable, resource } from '@angular/core'
 import { invoke } from '@tauri-apps/api/core'
-import { GetDevicesResponse } from './types'
+import { listen } from '@tauri-apps/api/event'
+import {
+  AccountInfo,
+  BatchReport,
+  BulbMatch,
+  DeviceListOptions,
+  DeviceSelector,
+  DeviceTree,
+  DiscoveryReport,
+  ExportFormat,
+  GetDevicesResponse,
+  Home,
+  MiotActionRequest,
+  MiotActionResult,
+  MiotPropertyRequest,
+  MiotPropertyResult,
+  MergedDevices,
+  MiotSpec,
+  PollStatus,
+  PropertyChange,
+  PropertyId,
+  Scene,
+} from './types'
 
 @Injectable({
   providedIn: 'root',
 })
 export class MiService {
   login(creds: { email: string; password: string }) {
-    return invoke('login', creds)
+    return invoke<AccountInfo>('add_account', creds)
+  }
+
+  loginAccount(account: string, password: string) {
+    return invoke<AccountInfo>('login_account', { account, password })
+  }
+
+  listAccounts() {
+    return invoke<AccountInfo[]>('list_accounts')
+  }
+
+  switchAccount(account: string) {
+    return invoke('switch_account', { account })
+  }
+
+  removeAccount(account?: string) {
+    return invoke('remove_account', { account })
+  }
+
+  getSession() {
+    return invoke<{ email: string | null; country: string } | null>(
+      'get_session'
+    )
+  }
+
+  logout() {
+    return this.removeAccount()
   }
 
   setCountry(country: string) {
@@ -20,8 +68,36 @@ export class MiService {
     return invoke<[code: string, name: string][]>('get_countries')
   }
 
-  getDevices() {
-    return invoke<GetDevicesResponse>('get_devices')
+  getDevices(options?: DeviceListOptions) {
+    return invoke<GetDevicesResponse>('get_devices', { options })
+  }
+
+  getDeviceTree() {
+    return invoke<DeviceTree>('get_device_tree')
+  }
+
+  getHomes() {
+    return invoke<Home[]>('get_homes')
+  }
+
+  /** Scenes of `homeId` or every home, only those referencing `did` if set */
+  getScenes(options?: { homeId?: string; did?: string }) {
+    return invoke<Scene[]>('get_scenes', {
+      homeId: options?.homeId,
+      did: options?.did,
+    })
+  }
+
+  runScene(scene: Scene) {
+    return invoke('run_scene', { scene })
+  }
+
+  setSceneEnabled(scene: Scene, enabled: boolean) {
+    return invoke('set_scene_enabled', { scene, enabled })
+  }
+
+  getAllDevices() {
+    return invoke<MergedDevices>('get_all_devices')
   }
 
   getDevice(did: string) {
@@ -30,11 +106,97 @@ export class MiService {
     )
   }
 
-  callDevice(data: { did: string; method: string; params?: string | null }) {
-    const { did, method } = data
+  /** Sent through gateway `parentId` if given, e.g. a device's `parent_id` */
+  callDevice(data: {
+    did: string
+    parentId?: string
+    method: string
+    params?: string | null
+  }) {
+    const { did, parentId, method } = data
+    let { params } = data
+    if ([null, ''].includes(params as '')) params = undefined
+    return invoke('call_device', { did, parentId, method, params })
+  }
+
+  callDevices(data: {
+    selector: DeviceSelector
+    method: string
+    params?: string | null
+    concurrency?: number
+  }) {
+    const { selector, method, concurrency } = data
     let { params } = data
     if ([null, ''].includes(params as '')) params = undefined
-    return invoke('call_device', { did, method, params })
+    return invoke<BatchReport>('call_devices', {
+      selector,
+      method,
+      params,
+      concurrency,
+    })
+  }
+
+  getLanControl(did: string) {
+    return invoke<boolean>('get_lan_control', { did })
+  }
+
+  /** Yeelight only, rejects if the bulb does not report the new state */
+  setLanControl(did: string, enabled: boolean) {
+    return invoke('set_lan_control', { did, enabled })
+  }
+
+  enableLanControlAll() {
+    return invoke<BatchReport>('enable_lan_control_all')
+  }
+
+  watchProperties(did: string, properties: PropertyId[], account?: string) {
+    return invoke('watch_device_properties', { account, did, properties })
+  }
+
+  unwatchProperties(did: string) {
+    return invoke('unwatch_device_properties', { did })
+  }
+
+  onPropertyChanged(handler: (change: PropertyChange) => void) {
+    return listen<PropertyChange>('device_property_changed', (e) =>
+      handler(e.payload)
+    )
+  }
+
+  onPollStatus(handler: (status: PollStatus) => void) {
+    return listen<PollStatus>('device_poll_status', (e) => handler(e.payload))
+  }
+
+  getProperties(params: MiotPropertyRequest[]) {
+    return invoke<MiotPropertyResult[]>('get_properties', { params })
+  }
+
+  setProperties(params: (MiotPropertyRequest & { value: any })[]) {
+    return invoke<MiotPropertyResult[]>('set_properties', { params })
+  }
+
+  callAction(params: MiotActionRequest) {
+    return invoke<MiotActionResult>('call_action', { params })
+  }
+
+  exportTokens(
+    format: ExportFormat,
+    path: string,
+    options?: DeviceListOptions
+  ) {
+    return invoke('export_tokens', { format, path, options })
+  }
+
+  getDeviceSpec(model: string) {
+    return invoke<MiotSpec>('get_device_spec', { model })
+  }
+
+  discoverDevices() {
+    return invoke<DiscoveryReport>('discover_devices')
+  }
+
+  discoverBulbs() {
+    return invoke<BulbMatch[]>('discover_bulbs')
   }
 
   getProp({ did, name }: { did: string; name: string | string[] }) {
@@ -13,7 +13,8 @@ import { AuthService } from '../auth.service'
 import { SetCountryDialogComponent } from '../dialogs/set-country-dialog/set-country-dialog.component'
 import { injectQuery } from '@tanstack/angular-query-experimental'
 import { ExecuteCommandDialogComponent } from '../dialogs/execute-command-dialog/execute-command-dialog.component'
-import { Device } from '../types'
+import { Device, DeviceNode } from '../types'
+import { Router } from '@angular/router'
 
 @Component({
   template: `
@@ -31,16 +32,28 @@ import { Device } from '../types'
       </button>
     </div>
 
+    <div class="tooltip fixed right-4 bottom-20 z-1" data-tip="Logout">
+      <button class="btn btn-circle btn-outline" (click)="logout()">
+        <app-icon class="w-6 h-6" icon="login" />
+      </button>
+    </div>
+
     <div
       class="p-4 {{
         devicesQuery.isFetching() && 'pointer-events-none opacity-60'
       }}"
     >
-      @for (device of devicesQuery.data(); track device.did) {
+      @for (row of rows(); track row.device.did) {
+        @if (row.orphan && !rows()[$index - 1]?.orphan) {
+          <div class="text-sm text-gray-500 mt-4 mb-2">
+            Sub-devices without a gateway
+          </div>
+        }
         <app-device
           class="mb-2"
-          [device]="device"
-          (executeCommand)="executeCommandForDevice.set(device)"
+          [style.margin-left.rem]="row.depth * 2"
+          [device]="row.device"
+          (executeCommand)="executeCommandForDevice.set(row.device)"
         ></app-device>
       } @empty {
         <div class="text-center text-gray-500">
@@ -86,27 +99,47 @@ export class DevicesPageComponent {
 
   miService = inject(MiService)
   authService = inject(AuthService)
+  router = inject(Router)
 
   deviceComponents = viewChildren(DeviceComponent)
 
   devicesQuery = injectQuery(() => ({
     queryKey: ['devices'],
-    queryFn: () => this.miService.getDevices(),
+    queryFn: () => this.miService.getDeviceTree(),
     staleTime: 1000 * 60 * 10,
     structuralSharing: false,
   }))
 
+  /** Tree flattened depth-first, sub-devices right after their gateway */
+  rows = computed(() => {
+    const tree = this.devicesQuery.data()
+    const rows: { device: Device; depth: number; orphan: boolean }[] = []
+    const visit = (node: DeviceNode, depth: number, orphan: boolean) => {
+      const { children, ...device } = node
+      rows.push({ device, depth, orphan })
+      children.forEach((child) => visit(child, depth + 1, orphan))
+    }
+    tree?.roots.forEach((node) => visit(node, 0, false))
+    tree?.orphans.forEach((node) => visit(node, 0, true))
+    return rows
+  })
+
   country = computed(() => {
     const user = this.authService.user()
     if (!user?.country) return null
     return this.miService.countryCodeToName().get(user.country)
   })
 
+  async logout() {
+    await this.authService.logout()
+    this.router.navigateByUrl('login')
+  }
+
   invalidateDevice() {
     const did = this.executeCommandForDevice()?.did
     if (!did) return
-    const index = this.devicesQuery.data()?.findIndex((d) => d.did === did)
-    if (typeof index === 'number' && index >= 0) {
+    const index = this.rows().findIndex((row) => row.device.did === did)
+    if (index >= 0) {
       this.deviceComponents().at(index)?.refreshDevice()
     }
   }
@@ -16,6 +16,7 @@ import { AuthService } from '../auth.service'
 import { DialogDirective } from '../dialogs/dialog.directive'
 import { IconComponent } from '../icon/icon.component'
 import { MiService } from '../mi.service'
+import { MiError } from '../types'
 
 @Component({
   template: `<form
@@ -53,13 +54,13 @@ import { MiService } from '../mi.service'
         Login
       </button>
 
-      @if (loginMutation.isError()) {
+      @if (loginMutation.isError() && !isCancelled(loginMutation.error())) {
         <div class="toast toast-center">
           <div role="alert" class="alert alert-error">
             <div></div>
             <div>
               <h3 class="font-bold">Login failed</h3>
-              <div class="text-xs" [innerHTML]="loginMutation.error()"></div>
+              <div class="text-xs" [innerHTML]="loginMutation.error()?.message"></div>
             </div>
             <button
               type="button"
@@ -182,6 +183,11 @@ export class LoginPageComponent {
   router = inject(Router)
   authService = inject(AuthService)
   miService = inject(MiService)
+  isCancelled(error: unknown) {
+    const { kind } = error as MiError
+    return kind === 'CaptchaCancelled' || kind === 'TwoFactorCancelled'
+  }
+
   loginMutation = injectMutation(() => ({
     mutationFn: (credentials: { email: string; password: string; country?: string }) =>
       this.authService.login(
@@ -3,7 +3,7 @@ export type Device = {
   bssid: string
   desc: string
   did: string
-  extra: Record<string, any>
+  extra: DeviceExtra
   family_id: number
   isOnline: Boolean
   latitude: string
@@ -26,6 +26,233 @@ export type Device = {
   ssid: string
   token: string
   uid: number
+  ownership: Ownership
+  /** Bind key of BLE devices, when fetched */
+  ble_key?: string
+}
+
+export type DeviceExtra = {
+  isSetPincode: number
+  fw_version: string
+  needVerifyCode: number
+  mcu_version: string
+  [key: string]: any
+}
+
+export type Ownership = 'owner' | 'shared'
+
+export type DeviceListOptions = {
+  shared?: boolean
+  virtual_models?: boolean
+  huami?: boolean
+  all_homes?: boolean
+  home_ids?: string[]
+  ble_keys?: boolean
 }
 
 export type GetDevicesResponse = Device[]
+
+export type DeviceNode = Device & {
+  /** Sub-devices paired with this gateway */
+  children: DeviceNode[]
+}
+
+export type DeviceTree = {
+  roots: DeviceNode[]
+  /** Sub-devices whose gateway is not in the list */
+  orphans: DeviceNode[]
+}
+
+export type Room = {
+  id: string
+  name: string
+  parent_id: string
+  dids: string[]
+}
+
+export type Home = {
+  /** Matches `Device.family_id` */
+  id: string
+  name: string
+  uid: number
+  /** Devices not placed in a room */
+  dids: string[]
+  rooms: Room[]
+}
+
+export type ExportFormat = 'home-assistant' | 'python-miio' | 'csv' | 'miio-cli'
+
+export type AccountInfo = {
+  user_id: string
+  username: string | null
+  country: string
+  active: boolean
+}
+
+export type MergedDevices = {
+  devices: (Device & { user_id: string })[]
+  errors: { user_id: string; error: MiError }[]
+}
+
+export type MiError = {
+  kind:
+    | 'NotLoggedIn'
+    | 'CaptchaCancelled'
+    | 'TwoFactorCancelled'
+    | 'InvalidCredentials'
+    | 'SessionExpired'
+    | 'UnknownAccount'
+    | 'UnsupportedCountry'
+    | 'Http'
+    | 'CloudError'
+    | 'DeviceError'
+    | 'UnknownModel'
+    | 'Unsupported'
+    | 'NotApplied'
+    | 'Decode'
+    | 'Login'
+    | 'Network'
+    | 'Timeout'
+    | 'Io'
+  message: string
+  code?: number
+  status?: number
+}
+
+export type DiscoveryReport = {
+  devices: {
+    device: Device
+    local_ip: string | null
+    localip_changed: boolean
+  }[]
+  unknown: { device_id: number; stamp: number; addr: string }[]
+}
+
+/** Yeelight bulb found on the LAN, `device` is `null` for other accounts */
+export type BulbMatch = {
+  id: string
+  addr: string
+  model: string
+  fw_ver: string
+  support: string[]
+  name: string
+  power: boolean
+  bright: number
+  color_mode: number
+  ct: number
+  rgb: number
+  hue: number
+  sat: number
+  device: Device | null
+}
+
+export type MiotPropertyRequest = { did: string; siid: number; piid: number }
+
+export type MiotPropertyResult = MiotPropertyRequest & {
+  code: number
+  value?: any
+}
+
+export type DeviceSelector =
+  | { by: 'dids'; dids: string[] }
+  /** `*` and `?` wildcards, e.g. `yeelink.light.*` */
+  | { by: 'model'; pattern: string }
+  | { by: 'home'; home_id: string; room_id?: string }
+
+export type BatchResult = { did: string; name: string; model: string } & (
+  | { status: 'ok'; result: any }
+  | { status: 'failed'; error: MiError }
+)
+
+export type BatchReport = { results: BatchResult[] }
+
+export type SceneTrigger = {
+  /** e.g. `event.lumi.sensor_magnet.v2.open` or `user.click` */
+  key: string
+  did: string
+  name: string
+  value: any
+}
+
+export type SceneAction = {
+  did: string
+  name: string
+  command: string
+  value: any
+}
+
+export type Scene = {
+  id: string
+  name: string
+  home_id: string
+  kind: 'manual' | 'automation'
+  enabled: boolean
+  triggers: SceneTrigger[]
+  actions: SceneAction[]
+  source: 'legacy' | 'miot'
+}
+
+/** miIO `get_prop` name or MIoT siid/piid pair */
+export type PropertyId = string | { siid: number; piid: number }
+
+export type PropertyChange = {
+  did: string
+  property: PropertyId
+  /** `null` on the first poll */
+  old: any | null
+  new: any
+}
+
+export type PollStatus =
+  | { kind: 'offline'; did: string; error: MiError; retry_in_secs: number }
+  | { kind: 'online'; did: string }
+
+export type MiotActionRequest = {
+  did: string
+  siid: number
+  aiid: number
+  in: any[]
+}
+
+export type MiotActionResult = {
+  did?: string
+  siid?: number
+  aiid?: number
+  code: number
+  out: any[]
+}
+
+export type MiotSpecProperty = {
+  iid: number
+  type: string
+  description: string
+  format: string
+  access: string[]
+  unit?: string
+  'value-range'?: [min: number, max: number, step: number]
+  'value-list'?: { value: any; description: string }[]
+}
+
+export type MiotSpec = {
+  type: string
+  description: string
+  services: {
+    iid: number
+    type: string
+    description: string
+    properties: MiotSpecProperty[]
+    actions: {
+      iid: number
+      type: string
+      description: string
+      in: number[]
+      out: number[]
+    }[]
+    events: {
+      iid: number
+      type: string
+      description: string
+      arguments: any[]
+    }[]
+  }[]
+}